@@ -37,8 +37,23 @@ shift
 ## Arch:
 
 pch="hook_write_pacman_conf"
-"${cleanroom}" dump-command "${pch}" >"commands/${pch}.dump"
-mv "commands/${pch}.dump" "commands/${pch}.toml"
+cat >"commands/${pch}.toml" <<EOF
+[command]
+help = """
+Write /etc/pacman.conf
+"""
+
+script = """
+    # Write the builtin pacman.conf
+    super_${pch}
+"""
+
+[command.phases.prepare]
+script = """
+    # Extend /etc/pacman.conf here, e.g. by adding more repositories:
+    # echo "[extra-repo]" >> /etc/pacman.conf
+"""
+EOF
 
 ## Set up Git:
 git="$("${busybox}" which git || true)"
@@ -5,13 +5,12 @@
 
 use anyhow::Context;
 
-pub fn run(command_prefix: &str, phase: &crate::Phases) -> anyhow::Result<()> {
+pub fn run(phase: &crate::Phases) -> anyhow::Result<()> {
     let agent_script = "/tmp/clrm/script.sh";
     let mut child = std::process::Command::new("/tmp/clrm/busybox")
         .arg("sh")
         .arg("-e")
         .arg(agent_script)
-        .arg(command_prefix)
         .arg(phase.to_string())
         .spawn()
         .context(format!(
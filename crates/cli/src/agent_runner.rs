@@ -3,7 +3,8 @@
 
 use crate::{
     commands::{CommandName, VariableName},
-    context::BuildContext,
+    context::{BuildContext, PhaseState, Provenance},
+    protocol::{Channel, LogLevel, Message},
     Phases,
 };
 
@@ -20,99 +21,117 @@ const DEFAULT_MACHINE_ID: [u8; 32] = [
     b'9', b'7', b'e', b'1', b'd', b'f', b'5', b'e', b'b', b'3', b'b', b'2', b'6', b'4', b'2', b'2',
 ];
 
-fn handle_set(cmd: &str, ctx: &mut BuildContext) -> anyhow::Result<bool> {
-    let Some(to_set) = cmd.strip_prefix("SET ") else {
-        return Ok(false);
-    };
-
-    let Some((k, v)) = to_set.split_once('=') else {
-        return Err(anyhow!(format!(
-            "Could not parse arguments after SET: No '=' found in {to_set:?}"
-        )));
-    };
-
-    let k = k.trim().trim_matches('"');
-    let v = v.trim().trim_matches('"');
-    ctx.set(k, v, false, false)
-        .context(format!("Failed fo SET {k} to {v}"))?;
-
-    Ok(true)
+/// Processes the messages the agent script sends in one phase
+struct MessageHandler {
+    phase: Phases,
+    /// The depth of the status stack before the agent script started
+    status_depth: usize,
+    current_status: Option<crate::printer::Headline>,
 }
 
-fn handle_set_ro(cmd: &str, ctx: &mut BuildContext) -> anyhow::Result<bool> {
-    let Some(to_set) = cmd.strip_prefix("SET_RO ") else {
-        return Ok(false);
-    };
-
-    let Some((k, v)) = to_set.split_once('=') else {
-        return Err(anyhow!(format!(
-            "Could not parse arguments after SET: No '=' found in {to_set:?}"
-        )));
-    };
-
-    let k = k.trim().trim_matches('"');
-    let v = v.trim().trim_matches('"');
-    ctx.set(k, v, true, false)
-        .context(format!("Failed fo SET_RO {k} to {v}"))?;
-
-    Ok(true)
-}
-
-fn handle_add_dependency(cmd: &str, ctx: &mut BuildContext) -> anyhow::Result<bool> {
-    let Some(to_set) = cmd.strip_prefix("ADD_DEPENDENCY ") else {
-        return Ok(false);
-    };
-
-    let Some((k, v)) = to_set.split_once('=') else {
-        return Err(anyhow!(format!(
-            "Could not parse arguments after ADD_DEPENDENCY: No '=' found in {to_set:?}"
-        )));
-    };
+impl MessageHandler {
+    fn new(ctx: &BuildContext, phase: &Phases) -> Self {
+        Self {
+            phase: phase.clone(),
+            status_depth: ctx.printer().status_stack().len(),
+            current_status: None,
+        }
+    }
 
-    let key = VariableName::try_from(k.trim().trim_matches('"').to_string())
-        .context(format!("Could not convert {k} to variable name"))?;
-    let value = CommandName::try_from(v.trim().trim_matches('"').to_string())
-        .context(format!("Could not convert {v} to command name"))?;
-    ctx.add_dependency(key.clone(), value.clone())
-        .context(format!("Failed to ADD_DEPENDENCY {key} using {value}"))?;
+    /// The command functions the agent script is currently running
+    fn command_stack(&self, ctx: &BuildContext) -> Vec<String> {
+        let status_stack = ctx.printer().status_stack();
+        status_stack[self.status_depth.min(status_stack.len())..].to_vec()
+    }
 
-    Ok(true)
-}
+    fn provenance(&self, ctx: &BuildContext) -> Provenance {
+        Provenance {
+            command_stack: self.command_stack(ctx),
+            phase: self.phase.clone(),
+        }
+    }
 
-fn parse_stdout(
-    m: &str,
-    command_prefix: &str,
-    ctx: &mut BuildContext,
-    current_status: &mut Option<crate::printer::Headline>,
-) -> anyhow::Result<bool> {
-    let p = ctx.printer();
-    let Some(cmd) = m.strip_prefix(command_prefix) else {
-        return Ok(false);
-    };
+    fn handle_message(&mut self, message: Message, ctx: &mut BuildContext) -> anyhow::Result<()> {
+        let provenance = self.provenance(ctx);
+        match message {
+            Message::Set { name, value } => ctx
+                .set_by(&name, &value, false, &provenance)
+                .context(format!("Failed to SET {name} to {value:?}")),
+            Message::SetRo { name, value } => ctx
+                .set_by(&name, &value, true, &provenance)
+                .context(format!("Failed to SET_RO {name} to {value:?}")),
+            Message::Append { name, value } => ctx
+                .append(&name, &value, &provenance)
+                .context(format!("Failed to APPEND {value:?} to {name}")),
+            Message::Remove { name, value } => ctx
+                .remove(&name, &value, &provenance)
+                .context(format!("Failed to REMOVE {value:?} from {name}")),
+            Message::Unset { name } => ctx
+                .unset(&name, &provenance)
+                .context(format!("Failed to UNSET {name}")),
+            Message::AddDependency { name, command } => {
+                let key = VariableName::try_from(name.clone())
+                    .context(format!("Could not convert {name} to variable name"))?;
+                let value = CommandName::try_from(command.clone())
+                    .context(format!("Could not convert {command} to command name"))?;
+                ctx.add_dependency(key.clone(), value.clone())
+                    .context(format!("Failed to ADD_DEPENDENCY {key} using {value}"))
+            }
+            Message::Status { message } => {
+                self.current_status = Some(
+                    ctx.printer()
+                        .push_headline(&message.to_string_lossy(), true),
+                );
+                Ok(())
+            }
+            Message::Push { message } => {
+                ctx.printer().push_status(&message.to_string_lossy());
+                Ok(())
+            }
+            Message::Pop => {
+                ctx.printer().pop_status();
+                Ok(())
+            }
+            Message::Log { level, message } => {
+                let p = ctx.printer();
+                let message = message.to_string_lossy();
+                match level {
+                    LogLevel::Warn => p.warn(&message),
+                    LogLevel::Info => p.info(&message),
+                    LogLevel::Debug => p.debug(&message),
+                }
+                Ok(())
+            }
+        }
+    }
 
-    p.trace(&format!("Processing {}", cmd));
-    if handle_set(cmd, ctx)? || handle_set_ro(cmd, ctx)? || handle_add_dependency(cmd, ctx)? {
-        return Ok(true);
+    fn process_line(&mut self, line: &str, ctx: &mut BuildContext) -> anyhow::Result<()> {
+        ctx.printer().trace(&format!("Processing {line}"));
+        self.handle_message(Message::parse(line)?, ctx)
     }
 
-    if let Some(status) = cmd.strip_prefix("STATUS ") {
-        let status = status.trim().trim_matches('"');
-        *current_status = Some(ctx.printer().push_headline(status, true));
-    } else if let Some(status) = cmd.strip_prefix("PUSH ") {
-        let status = status.trim().trim_matches('"');
-        ctx.printer().push_status(status);
-    } else if cmd == "POP" {
-        ctx.printer().pop_status();
-    } else {
-        return Err(anyhow!(format!(
-            "Agent asked to process unknown command {cmd:?}"
-        )));
+    /// Process messages arriving on `channel` until reading from it fails
+    async fn receive(
+        &mut self,
+        channel: &mut Channel,
+        ctx: &mut BuildContext,
+    ) -> anyhow::Result<std::convert::Infallible> {
+        loop {
+            let line = channel.next_line().await?;
+            if let Err(e) = self.process_line(&line, ctx) {
+                ctx.printer()
+                    .error(&format!("Failed to process agent message: {e:?}"));
+            }
+        }
     }
+}
 
-    Ok(true)
+/// Where the protocol channel of `ctx` lives on the host
+fn channel_path(ctx: &BuildContext) -> PathBuf {
+    ctx.scratch_directory().join("channel")
 }
 
-fn run_in_bootstrap(phase: &Phases) -> bool {
+pub(crate) fn run_in_bootstrap(phase: &Phases) -> bool {
     phase == &Phases::Install || phase == &Phases::BuildArtifacts || phase == &Phases::TestArtifacts
 }
 
@@ -124,18 +143,50 @@ fn mount_root_fs(phase: &Phases) -> bool {
     phase != &Phases::TestArtifacts
 }
 
+/// The line number of the agent script in a shell error message
+fn script_line(message: &str) -> Option<usize> {
+    let (_, rest) = message.split_once("/tmp/clrm/script.sh: line ")?;
+    let digits = rest.split(|c: char| !c.is_ascii_digit()).next()?;
+    digits.parse().ok()
+}
+
+/// Describe where in the command definitions the agent script failed
+fn failure_report(
+    source_map: &crate::scripts::SourceMap,
+    status_stack: &[String],
+    failed_line: Option<usize>,
+) -> String {
+    let mut report = String::from("The agent script failed");
+    if !status_stack.is_empty() {
+        report += &format!("\n  command stack: {}", status_stack.join(" -> "));
+    }
+    if let Some(location) = status_stack
+        .last()
+        .and_then(|function| source_map.function(function))
+    {
+        report += &format!("\n  failing function: {location}");
+    }
+    if let Some(line) = failed_line {
+        match source_map.lookup(line) {
+            Some(location) => report += &format!("\n  script line {line}: {location}"),
+            None => report += &format!("\n  script line {line}"),
+        }
+    }
+    report
+}
+
 fn create_runner(
     ctx: &BuildContext,
     command: &CommandName,
     phase: &Phases,
     extra_bindings: &[String],
-) -> anyhow::Result<Runner<contained_command::Nspawn>> {
+) -> anyhow::Result<(Runner<contained_command::Nspawn>, crate::scripts::SourceMap)> {
     let p = ctx.printer();
     let contained_artifacts = "/tmp/clrm/artifacts";
 
     let _hl = p.push_headline(&format!("Create \"{phase}\""), true);
-    let agent_script =
-        crate::scripts::create_script(ctx, command).context("Failed to create agent script")?;
+    let agent_script = crate::scripts::create_script(ctx, command, phase)
+        .context("Failed to create agent script")?;
     p.trace("Agent script: {agent_script:?}");
 
     let mut flags = vec![];
@@ -188,9 +239,32 @@ fn create_runner(
             &PathBuf::from("/tmp/clrm/busybox"),
         ))
         .binding(Binding::ro(
-            &agent_script,
+            &agent_script.script,
             &PathBuf::from("/tmp/clrm/script.sh"),
+        ))
+        .binding(Binding::ro(
+            &agent_script.bodies_directory,
+            &PathBuf::from(crate::scripts::BODIES_DIRECTORY),
+        ))
+        .binding(Binding::ro(
+            &agent_script.protocol_helper,
+            &PathBuf::from(crate::scripts::PROTOCOL_HELPER),
+        ))
+        .binding(Binding::rw(
+            &channel_path(ctx),
+            &PathBuf::from(crate::protocol::CHANNEL),
+        ));
+
+    let data_scratch_directory = ctx.scratch_directory().join("data");
+    for (function, data) in crate::scripts::data_directories(ctx.command_manager(), command) {
+        let host_path = data
+            .host_path(&data_scratch_directory)
+            .context(format!("Failed to provide data directory of {function}"))?;
+        runner = runner.binding(Binding::ro(
+            &host_path,
+            &PathBuf::from(crate::scripts::DATA_DIRECTORY).join(&function),
         ));
+    }
 
     for extra in extra_bindings {
         let binding =
@@ -210,10 +284,13 @@ fn create_runner(
             .env("ARTIFACTS_DIR", contained_artifacts)
     }
 
-    Ok(runner.description(flags.join(", ").to_string()))
+    Ok((
+        runner.description(flags.join(", ").to_string()),
+        agent_script.source_map,
+    ))
 }
 
-#[allow(clippy::needless_pass_by_ref_mut)] // FIXME: It's not useless: It's passed on to parse_stdout!
+#[allow(clippy::needless_pass_by_ref_mut)] // Mirrors run_agent_phase, which does modify ctx
 pub async fn enter_agent_phase(
     ctx: &mut BuildContext,
     command: &CommandName,
@@ -221,7 +298,11 @@ pub async fn enter_agent_phase(
     extra_bindings: &[String],
 ) -> anyhow::Result<()> {
     let p = ctx.printer();
-    let runner = create_runner(ctx, command, phase, extra_bindings)?.with_network();
+    // The agent script is not run, but the channel must exist to be mounted
+    let _channel = Channel::create(&channel_path(ctx))?;
+    let runner = create_runner(ctx, command, phase, extra_bindings)?
+        .0
+        .with_network();
     let _hl = p.push_headline(
         &format!("Enter container in \"{phase}\" [{}]", runner.describe()),
         false,
@@ -252,7 +333,6 @@ pub async fn enter_agent_phase(
     }
 }
 
-#[allow(clippy::needless_pass_by_ref_mut)] // FIXME: It's not useless: It's passed on to parse_stdout!
 pub async fn run_agent_phase(
     ctx: &mut BuildContext,
     command: &CommandName,
@@ -260,40 +340,47 @@ pub async fn run_agent_phase(
     extra_bindings: &[String],
 ) -> anyhow::Result<()> {
     let p = ctx.printer();
-    let runner = create_runner(ctx, command, phase, extra_bindings)?;
+    let mut channel = Channel::create(&channel_path(ctx))?;
+    let (runner, source_map) = create_runner(ctx, command, phase, extra_bindings)?;
     let _hl = p.push_headline(
         &format!("Running Agent in \"{phase}\" [{}]", runner.describe()),
         false,
     );
 
-    let command_prefix = uuid::Uuid::new_v4().to_string();
     let command = {
         let mut command = Command::new("/tmp/clrm/agent");
         command.arg("build-agent");
-        command.arg(&format!("--command-prefix={command_prefix}"));
-        command.arg(&phase.to_string());
+        command.arg(phase.to_string());
         command
     };
 
-    let command_prefix = format!("{command_prefix}: ");
-    {
-        let mut current_status = None;
-        runner
-            .run(
-                &command,
-                &|m| p.trace(m),
-                &|m| p.error(m),
-                &mut |m| {
-                    match parse_stdout(m, &command_prefix, ctx, &mut current_status) {
-                        Ok(true) => {}
-                        Ok(false) => p.print_stdout(m),
-                        Err(e) => p.error(&format!("Failed to parse stdout: {e:?}")),
-                    };
-                },
-                &mut |m| p.print_stderr(m),
-            )
-            .await
-            .context("Failed to containerize")?;
+    let mut handler = MessageHandler::new(ctx, phase);
+    let mut failed_line = None;
+    let result = {
+        let trace = |m: &str| p.trace(m);
+        let error = |m: &str| p.error(m);
+        let mut stdout = |m: &str| p.print_stdout(m);
+        let mut stderr = |m: &str| {
+            failed_line = script_line(m).or(failed_line);
+            p.print_stderr(m);
+        };
+        let run = runner.run(&command, &trace, &error, &mut stdout, &mut stderr);
+        tokio::select! {
+            result = run => result.map_err(|e| anyhow::Error::from(e).context("Failed to containerize")),
+            Err(e) = handler.receive(&mut channel, ctx) => Err(e),
+        }
+    };
+    for line in channel.drain()? {
+        if let Err(e) = handler.process_line(&line, ctx) {
+            p.error(&format!("Failed to process agent message: {e:?}"));
+        }
+    }
+    if let Err(e) = result {
+        return Err(e.context(failure_report(
+            &source_map,
+            &handler.command_stack(ctx),
+            failed_line,
+        )));
     }
 
     Ok(())
@@ -313,18 +400,34 @@ pub async fn run_build_agent(
             p.debug(&format!("RunContext in {phase} is:\n{ctx}"));
         }
         if enter_phase.as_ref() == Some(phase) {
+            ctx.persist(phase, PhaseState::Entered)
+                .context(format!("Failed to persist the build context in {phase}"))?;
             return enter_agent_phase(ctx, command, phase, extra_bindings).await;
         } else {
-            run_agent_phase(ctx, command, phase, extra_bindings).await?;
+            ctx.persist(phase, PhaseState::Running)
+                .context(format!("Failed to persist the build context in {phase}"))?;
+            let result = run_agent_phase(ctx, command, phase, extra_bindings).await;
+            let state = if result.is_ok() {
+                PhaseState::Succeeded
+            } else {
+                PhaseState::Failed
+            };
+            let persisted = ctx
+                .persist(phase, state)
+                .context(format!("Failed to persist the build context after {phase}"));
+            result?;
+            persisted?;
             let dependencies = ctx.take_dependencies();
 
             for (name, command) in dependencies {
                 let _hl =
                     p.push_headline(&format!("Building Dependency {name} => {command}"), true);
 
-                let mut dep_ctx = ctx.create_dependent_context(&name).context(format!(
-                    "Failed to create dependent context for dependency {name}"
-                ))?;
+                let mut dep_ctx =
+                    ctx.create_dependent_context(&name, &command)
+                        .context(format!(
+                            "Failed to create dependent context for dependency {name}"
+                        ))?;
 
                 run_build_agent(&mut dep_ctx, &command, &None, extra_bindings)
                     .await
@@ -342,77 +445,206 @@ pub async fn run_build_agent(
 mod tests {
     use super::*;
 
-    fn test_parse_stdout(
-        input: &str,
-        command_prefix: &str,
-        expect_handled: bool,
-        expect_error: bool,
-    ) -> crate::context::BuildContext {
+    fn test_process_line(line: &str, expect_error: bool) -> crate::context::BuildContext {
         let ctx = crate::context::ContextBuilder::new_test().build().unwrap();
         let mut ctx = ctx.test_system();
 
         ctx.set("FOO", "bar", false, false).unwrap();
         ctx.set("BAR", "foo", false, false).unwrap();
 
-        let mut current_headline = None;
+        let mut handler = MessageHandler::new(&ctx, &Phases::Install);
 
-        match parse_stdout(input, command_prefix, &mut ctx, &mut current_headline) {
-            Ok(h) => {
-                assert!(!expect_error);
-                assert_eq!(h, expect_handled);
-            }
-            Err(_) => assert!(expect_error),
-        };
+        assert_eq!(handler.process_line(line, &mut ctx).is_err(), expect_error);
         ctx
     }
 
     #[test]
-    fn test_parse_stdout_invalid_command() {
-        let _ = test_parse_stdout(&"PFX: XXXX FOO=baz", &"PFX: ", true, true);
+    fn test_script_line() {
+        assert_eq!(
+            script_line("/tmp/clrm/script.sh: line 42: foo: not found"),
+            Some(42)
+        );
+        assert_eq!(script_line("sh: foo: not found"), None);
     }
 
     #[test]
-    fn test_parse_stdout_not_a_command() {
-        let _ = test_parse_stdout(&"SET FOO=baz", &"PFX: ", false, false);
+    fn test_failure_report() {
+        let source_map = crate::scripts::SourceMap::default();
+        assert_eq!(
+            failure_report(
+                &source_map,
+                &["foo".to_string(), "bar".to_string()],
+                Some(7)
+            ),
+            "The agent script failed\n  command stack: foo -> bar\n  script line 7"
+        );
     }
 
     #[test]
-    fn test_parse_stdout_set_overwrite_ok() {
-        let ctx = test_parse_stdout(&"PFX: SET FOO=baz", &"PFX: ", true, false);
+    fn test_process_line_invalid_verb() {
+        let _ = test_process_line(r#"{"verb":"XXXX","name":"FOO","value":""}"#, true);
+    }
 
-        assert_eq!(ctx.get("FOO"), Some("baz".to_string()));
+    #[test]
+    fn test_process_line_not_json() {
+        let ctx = test_process_line("SET FOO=baz", true);
+
+        assert_eq!(ctx.get("FOO"), Some("bar".to_string()));
     }
 
     #[test]
-    fn test_parse_stdout_quoted_set_overwrite_ok() {
-        let ctx = test_parse_stdout(&"PFX: SET \"FOO\"=\"baz\"", &"PFX: ", true, false);
+    fn test_process_line_set_overwrite_ok() {
+        let ctx = test_process_line(r#"{"verb":"SET","name":"FOO","value":"62617a"}"#, false);
 
         assert_eq!(ctx.get("FOO"), Some("baz".to_string()));
     }
 
     #[test]
-    fn test_parse_stdout_set_add_ok() {
-        let ctx = test_parse_stdout(&"PFX: SET BAZ=baz", &"PFX: ", true, false);
+    fn test_process_line_set_add_ok() {
+        let ctx = test_process_line(r#"{"verb":"SET","name":"BAZ","value":"62617a"}"#, false);
 
         assert_eq!(ctx.get("BAZ"), Some("baz".to_string()));
     }
 
     #[test]
-    fn test_parse_stdout_quoted_set_add_ok() {
-        let ctx = test_parse_stdout(&"PFX: SET \"BAZ\"=\"baz\"", &"PFX: ", true, false);
+    fn test_process_line_set_binary_ok() {
+        let ctx = test_process_line(
+            r#"{"verb":"SET_RO","name":"BAZ","value":"20220a2220ff"}"#,
+            false,
+        );
+
+        assert_eq!(
+            ctx.get_raw("BAZ"),
+            Some(std::os::unix::ffi::OsStringExt::from_vec(
+                b" \"\n\" \xff".to_vec()
+            ))
+        );
+    }
 
-        assert_eq!(ctx.get("BAZ"), Some("baz".to_string()));
+    #[test]
+    fn test_process_line_log() {
+        let ctx = test_process_line(r#"{"verb":"LOG","level":"info","message":"6869"}"#, false);
+
+        assert_eq!(ctx.printer().error_count(), 0);
+    }
+
+    fn process_lines(ctx: &mut crate::context::BuildContext, lines: &[&str]) -> bool {
+        let mut handler = MessageHandler::new(ctx, &Phases::Install);
+        lines.iter().all(|l| handler.process_line(l, ctx).is_ok())
+    }
+
+    #[test]
+    fn test_process_line_lists() {
+        let mut ctx = test_process_line(r#"{"verb":"UNSET","name":"FOO"}"#, false);
+        assert_eq!(ctx.get("FOO"), None);
+
+        assert!(process_lines(
+            &mut ctx,
+            &[
+                r#"{"verb":"APPEND","name":"FOO","value":"61"}"#,
+                r#"{"verb":"APPEND","name":"FOO","value":"62"}"#,
+                r#"{"verb":"APPEND","name":"FOO","value":"61"}"#,
+                r#"{"verb":"APPEND","name":"FOO","value":"63"}"#,
+                r#"{"verb":"REMOVE","name":"FOO","value":"62"}"#,
+                r#"{"verb":"REMOVE","name":"FOO","value":"64"}"#,
+            ]
+        ));
+        assert_eq!(ctx.get("FOO"), Some("a c".to_string()));
+
+        // Empty variables turn into lists, others do not
+        assert!(process_lines(
+            &mut ctx,
+            &[
+                r#"{"verb":"SET","name":"EMPTY","value":""}"#,
+                r#"{"verb":"APPEND","name":"EMPTY","value":"61"}"#,
+            ]
+        ));
+        assert_eq!(ctx.get("EMPTY"), Some("a".to_string()));
+        assert!(!process_lines(
+            &mut ctx,
+            &[r#"{"verb":"APPEND","name":"BAR","value":"61"}"#]
+        ));
+        assert!(!process_lines(
+            &mut ctx,
+            &[r#"{"verb":"APPEND","name":"FOO","value":"6120"}"#]
+        ));
+
+        assert!(process_lines(
+            &mut ctx,
+            &[r#"{"verb":"SET_RO","name":"FOO","value":"78"}"#]
+        ));
+        assert!(!process_lines(
+            &mut ctx,
+            &[r#"{"verb":"UNSET","name":"FOO"}"#]
+        ));
     }
 
     #[test]
-    fn test_parse_stdout_set_invalid_name() {
-        let ctx = test_parse_stdout(&"PFX: SET foo=baz", &"PFX: ", true, true);
+    fn test_process_line_set_invalid_name() {
+        let ctx = test_process_line(r#"{"verb":"SET","name":"foo","value":"62617a"}"#, true);
 
         assert_eq!(ctx.get("FOO"), Some("bar".to_string()));
     }
 
     #[test]
-    fn test_parse_stdout_set_no_equal() {
-        let _ = test_parse_stdout(&"PFX: SET FOOBAR", &"PFX: ", true, true);
+    fn test_process_line_provenance() {
+        let ctx = crate::context::ContextBuilder::new_test().build().unwrap();
+        let mut ctx = ctx.test_system();
+        let mut handler = MessageHandler::new(&ctx, &Phases::Prepare);
+
+        assert!(handler
+            .process_line(r#"{"verb":"PUSH","message":"666f6f"}"#, &mut ctx)
+            .is_ok());
+        assert!(handler
+            .process_line(r#"{"verb":"PUSH","message":"626172"}"#, &mut ctx)
+            .is_ok());
+        assert!(handler
+            .process_line(r#"{"verb":"SET_RO","name":"FOO","value":"78"}"#, &mut ctx)
+            .is_ok());
+        assert!(handler.process_line(r#"{"verb":"POP"}"#, &mut ctx).is_ok());
+        assert!(handler
+            .process_line(r#"{"verb":"APPEND","name":"BAR","value":"78"}"#, &mut ctx)
+            .is_ok());
+
+        let mut handler = MessageHandler::new(&ctx, &Phases::Install);
+        let error = handler
+            .process_line(r#"{"verb":"SET","name":"FOO","value":"79"}"#, &mut ctx)
+            .unwrap_err();
+        assert!(format!("{error:?}").contains("read-only by foo -> bar in phase prepare"));
+
+        let display = ctx.to_string();
+        assert!(display.contains("set by foo -> bar in phase prepare"));
+        assert!(display.contains("set by foo in phase prepare"));
+
+        // Unsetting a variable is recorded as well
+        assert!(handler
+            .process_line(r#"{"verb":"UNSET","name":"BAR"}"#, &mut ctx)
+            .is_ok());
+        assert_eq!(ctx.get("BAR"), None);
+        assert_eq!(
+            ctx.provenance("BAR").unwrap().to_string(),
+            "the agent script in phase install"
+        );
+        assert!(ctx
+            .to_string()
+            .contains("\"BAR\" unset by the agent script in phase install"));
+
+        // Internal changes replace what the agent script did before
+        ctx.set("BAR", "internal", false, false).unwrap();
+        assert_eq!(ctx.provenance("BAR"), None);
+        assert!(!ctx.to_string().contains("unset by"));
+        let mut handler = MessageHandler::new(&ctx, &Phases::Polish);
+        assert!(handler
+            .process_line(r#"{"verb":"SET","name":"BAR","value":"79"}"#, &mut ctx)
+            .is_ok());
+        assert_eq!(
+            ctx.provenance("BAR").unwrap().to_string(),
+            "the agent script in phase polish"
+        );
+        ctx.set("BAR", "internal", false, false).unwrap();
+        assert_eq!(ctx.provenance("BAR"), None);
+        assert!(!ctx
+            .to_string()
+            .contains("set by the agent script in phase polish"));
     }
 }
@@ -0,0 +1,1316 @@
+// Copyright © Tobias Hunger <tobias.hunger@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Static analysis of the calls between `Command`s
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::commands::{Command, CommandManager, CommandName};
+
+/// How bad an `Issue` is
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in the definition of a `Command`
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Issue {
+    pub severity: Severity,
+    pub command: CommandName,
+    pub message: String,
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: {}: {}", self.command, self.message)
+    }
+}
+
+/// An argument passed in a `Call`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Argument {
+    /// A fixed value
+    Literal(String),
+    /// The value of a variable (`"${foo}"`)
+    Variable(String),
+    /// Anything else
+    Unknown,
+}
+
+impl Argument {
+    fn new(word: &Word) -> Self {
+        let text = word.text.as_str();
+        if !text.contains('$') && !text.contains('`') {
+            return Argument::Literal(text.to_string());
+        }
+        let name = text
+            .strip_prefix("${")
+            .and_then(|t| t.strip_suffix('}'))
+            .or_else(|| text.strip_prefix('$'));
+        match name {
+            Some(name)
+                if !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                Argument::Variable(name.to_string())
+            }
+            _ => Argument::Unknown,
+        }
+    }
+}
+
+/// A call from one `Command` to another
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// The `Command` that is called
+    pub callee: CommandName,
+    /// The number of arguments passed. `None` if that is not statically known
+    pub argument_count: Option<usize>,
+    /// The arguments passed
+    pub arguments: Vec<Argument>,
+    /// The `Command` is not called directly, but registered via `add_dependency`
+    pub is_dependency: bool,
+}
+
+/// A word in a shell script
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Word {
+    pub(crate) text: String,
+    /// The word contains an unquoted expansion (or `"$@"`), so it might turn
+    /// into any number of arguments
+    pub(crate) is_splitting: bool,
+    /// The word is a redirection target
+    pub(crate) is_redirect: bool,
+    /// The word contains quotes
+    is_quoted: bool,
+}
+
+#[derive(Default)]
+struct Scanner {
+    commands: Vec<Vec<Word>>,
+    current: Vec<Word>,
+    word: Option<Word>,
+    next_is_redirect: bool,
+    next_is_heredoc: bool,
+    /// Delimiters of pending here documents and whether their body is expanded
+    heredocs: Vec<(String, bool)>,
+    functions: Vec<String>,
+    variables: BTreeSet<String>,
+    /// The variables used by each entry of `commands`
+    command_variables: Vec<BTreeSet<String>>,
+    current_variables: BTreeSet<String>,
+}
+
+impl Scanner {
+    fn word(&mut self) -> &mut Word {
+        self.word.get_or_insert_with(Default::default)
+    }
+
+    fn end_word(&mut self) {
+        let Some(mut w) = self.word.take() else {
+            return;
+        };
+        if self.next_is_heredoc {
+            self.heredocs
+                .push((w.text.trim_start_matches('-').to_string(), !w.is_quoted));
+        } else {
+            w.is_redirect = self.next_is_redirect;
+            self.current.push(w);
+        }
+        self.next_is_heredoc = false;
+        self.next_is_redirect = false;
+    }
+
+    fn end_command(&mut self) {
+        self.end_word();
+        let variables = std::mem::take(&mut self.current_variables);
+        if !self.current.is_empty() {
+            self.commands.push(std::mem::take(&mut self.current));
+            self.command_variables.push(variables);
+        } else if let Some(last) = self.command_variables.last_mut() {
+            last.extend(variables);
+        }
+    }
+
+    fn add_variable(&mut self, name: &str) {
+        if !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) {
+            self.variables.insert(name.to_string());
+            // Checking whether a variable is set is no real use of it
+            let is_tested = self
+                .current
+                .last()
+                .is_some_and(|w| w.text == "-n" || w.text == "-z");
+            if !is_tested {
+                self.current_variables.insert(name.to_string());
+            }
+        }
+    }
+
+    /// Record all variables expanded in `text`, which contains no quoting
+    fn add_variables_in(&mut self, text: &str) {
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '$' => {
+                    chars.next_if_eq(&'{');
+                    let name = take_name(&mut chars);
+                    self.add_variable(&name);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Consume a variable name following a `$` or `${`
+fn take_name(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut name = String::new();
+    while let Some(n) = chars.next_if(|n| n.is_ascii_alphanumeric() || *n == '_') {
+        name.push(n);
+    }
+    name
+}
+
+/// Split a shell script into simple commands, each being a list of words,
+/// and collect the functions it defines and the variables it expands
+///
+/// This is not a real shell parser: It is good enough to find calls to other
+/// commands in the scripts cleanroom ships, but will be confused by more
+/// exotic shell constructs (e.g. commands inside of double quotes).
+fn scan(script: &str) -> Scanner {
+    let mut scanner = Scanner::default();
+    let mut chars = script.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => scanner.end_word(),
+            '\\' => match chars.next() {
+                Some('\n') | None => {}
+                Some(n) => scanner.word().text.push(n),
+            },
+            '\n' => {
+                scanner.end_command();
+                for (delimiter, expands) in std::mem::take(&mut scanner.heredocs) {
+                    loop {
+                        let line: String = chars.by_ref().take_while(|c| *c != '\n').collect();
+                        if line.trim() == delimiter || chars.peek().is_none() {
+                            break;
+                        }
+                        if expands {
+                            scanner.add_variables_in(&line);
+                            // The here document belongs to the command before it
+                            let variables = std::mem::take(&mut scanner.current_variables);
+                            if let Some(last) = scanner.command_variables.last_mut() {
+                                last.extend(variables);
+                            }
+                        }
+                    }
+                }
+            }
+            '#' if scanner.word.is_none() => while chars.next_if(|c| *c != '\n').is_some() {},
+            '\'' => {
+                let w = scanner.word();
+                w.is_quoted = true;
+                for n in chars.by_ref() {
+                    if n == '\'' {
+                        break;
+                    }
+                    w.text.push(n);
+                }
+            }
+            '"' => {
+                scanner.word().is_quoted = true;
+                while let Some(n) = chars.next() {
+                    match n {
+                        '"' => break,
+                        '\\' => {
+                            if let Some(e) = chars.next() {
+                                scanner.word().text.push(e);
+                            }
+                        }
+                        '$' => {
+                            let w = scanner.word();
+                            w.text.push(n);
+                            if let Some(b) = chars.next_if_eq(&'{') {
+                                w.text.push(b);
+                            }
+                            if chars.peek() == Some(&'@') {
+                                w.is_splitting = true;
+                            }
+                            let name = take_name(&mut chars);
+                            w.text.push_str(&name);
+                            scanner.add_variable(&name);
+                        }
+                        _ => scanner.word().text.push(n),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'(') => {
+                chars.next();
+                scanner.end_command();
+            }
+            '$' => {
+                let w = scanner.word();
+                w.text.push(c);
+                w.is_splitting = true;
+                let braced = chars.next_if_eq(&'{').is_some();
+                let name = take_name(&mut chars);
+                if braced {
+                    w.text.push('{');
+                    w.text.push_str(&name);
+                    for n in chars.by_ref() {
+                        w.text.push(n);
+                        if n == '}' {
+                            break;
+                        }
+                    }
+                } else {
+                    w.text.push_str(&name);
+                }
+                scanner.add_variable(&name);
+            }
+            '(' if chars.peek() == Some(&')') && scanner.current.is_empty() => {
+                // Function definition: `name() {`
+                chars.next();
+                if let Some(w) = scanner.word.take() {
+                    scanner.functions.push(w.text);
+                }
+            }
+            ';' | '&' | '|' | '(' | ')' | '`' => scanner.end_command(),
+            '<' | '>' => {
+                if scanner
+                    .word
+                    .as_ref()
+                    .is_some_and(|w| w.text.chars().all(|c| c.is_ascii_digit()))
+                {
+                    // File descriptor number, e.g. `2>`
+                    scanner.word = None;
+                }
+                scanner.end_word();
+                if c == '<' && chars.next_if_eq(&'<').is_some() {
+                    scanner.next_is_heredoc = true;
+                } else {
+                    chars.next_if(|c| *c == '>' || *c == '&');
+                    scanner.next_is_redirect = true;
+                }
+            }
+            _ => scanner.word().text.push(c),
+        }
+    }
+    scanner.end_command();
+
+    scanner
+}
+
+/// The command selecting the distribution to build
+const DISTRIBUTION_COMMAND: &str = "_distribution";
+
+const RESERVED_WORDS: [&str; 13] = [
+    "!", "{", "}", "do", "done", "elif", "else", "esac", "fi", "if", "then", "until", "while",
+];
+
+/// The name of the variable assigned to by `word`, if it is an assignment
+pub(crate) fn assigned_variable(word: &str) -> Option<&str> {
+    word.split_once('=').map(|(name, _)| name).filter(|name| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// Does `word` compute the name of a command at runtime?
+///
+/// That is the case if `word` mixes expansions with text allowed in command
+/// names, like `_distribution_${id}`. Words that are expansions only (like
+/// `"${BUSYBOX}"`) or paths are assumed to run binaries.
+fn computes_command_name(word: &str) -> bool {
+    let mut literal = String::new();
+    let mut is_expanding = false;
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => {
+                is_expanding = true;
+                let close = match chars.peek() {
+                    Some('{') => '}',
+                    Some('(') => ')',
+                    _ => {
+                        if take_name(&mut chars).is_empty() {
+                            chars.next();
+                        }
+                        continue;
+                    }
+                };
+                chars.by_ref().find(|c| *c == close);
+            }
+            '`' => {
+                is_expanding = true;
+                chars.by_ref().find(|c| *c == '`');
+            }
+            _ => literal.push(c),
+        }
+    }
+    is_expanding
+        && !literal.is_empty()
+        && literal
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// A simple command in a shell script
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SimpleCommand {
+    /// Variables assigned to in front of the command
+    pub(crate) assignments: Vec<String>,
+    /// The command followed by its arguments, without any redirections
+    pub(crate) words: Vec<Word>,
+    /// The variables expanded by the command, except for those it only
+    /// checks for being empty
+    pub(crate) variables: BTreeSet<String>,
+    /// The `if` statements the command is nested in, outermost first: The
+    /// number of the statement in the script and the branch taken in it
+    pub(crate) branches: Vec<(usize, usize)>,
+}
+
+/// A shell script split into simple commands
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Script {
+    pub(crate) commands: Vec<SimpleCommand>,
+    /// The names of all functions defined in the script
+    pub(crate) functions: Vec<String>,
+    /// The names of all variables expanded in the script
+    pub(crate) variables: BTreeSet<String>,
+}
+
+pub(crate) fn parse(script: &str) -> Script {
+    let scanner = scan(script);
+    let mut branches: Vec<(usize, usize)> = vec![];
+    let mut if_count = 0;
+    let commands = scanner
+        .commands
+        .into_iter()
+        .zip(scanner.command_variables)
+        .map(|(words, variables)| {
+            let mut result = SimpleCommand {
+                variables,
+                ..Default::default()
+            };
+            for w in words.into_iter().filter(|w| !w.is_redirect) {
+                if !result.words.is_empty() {
+                    result.words.push(w);
+                } else if let Some(name) = assigned_variable(&w.text) {
+                    result.assignments.push(name.to_string());
+                } else if !RESERVED_WORDS.contains(&w.text.as_str()) {
+                    result.words.push(w);
+                } else if !w.is_quoted {
+                    match w.text.as_str() {
+                        "if" => {
+                            branches.push((if_count, 0));
+                            if_count += 1;
+                        }
+                        "elif" | "else" => {
+                            if let Some((_, branch)) = branches.last_mut() {
+                                *branch += 1;
+                            }
+                        }
+                        "fi" => {
+                            branches.pop();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            result.branches = branches.clone();
+            result
+        })
+        .collect();
+
+    Script {
+        commands,
+        functions: scanner.functions,
+        variables: scanner.variables,
+    }
+}
+
+/// The names of all functions defined in the agent script header
+fn header_functions() -> BTreeSet<String> {
+    parse(&crate::scripts::header_script())
+        .functions
+        .into_iter()
+        .collect()
+}
+
+/// The calls made by one `Command`
+#[derive(Clone, Debug, Default)]
+pub struct CommandCalls {
+    /// All calls to known `Command`s
+    pub calls: Vec<Call>,
+    /// Words in command position that look like `Command`s, but are not known
+    pub unknown: BTreeSet<String>,
+    /// Some calls can not be resolved statically: The command computes the
+    /// names of commands to run, or has its body run by an interpreter
+    pub is_opaque: bool,
+    /// A body run by an interpreter mentions `super_<name>`, which only
+    /// shell code can call
+    pub calls_super_from_interpreter: bool,
+}
+
+/// Does `text` contain `word`, not being part of a longer name?
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(word).any(|(pos, _)| {
+        !text[..pos].ends_with(is_name_char) && !text[pos + word.len()..].starts_with(is_name_char)
+    })
+}
+
+impl CommandCalls {
+    fn new(
+        name: &CommandName,
+        command: &Command,
+        manager: &CommandManager,
+        header: &BTreeSet<String>,
+    ) -> Self {
+        let super_name = format!("super_{name}");
+
+        // Shadowed definitions can be called from the command via `super_<name>`
+        let mut definitions = vec![];
+        let mut current = Some(command);
+        while let Some(c) = current {
+            definitions.push(c);
+            current = c.overwritten();
+        }
+
+        let mut result = Self {
+            is_opaque: definitions.iter().any(|d| d.interpreter().is_some()),
+            calls_super_from_interpreter: definitions
+                .iter()
+                .filter(|d| d.interpreter().is_some() && d.overwritten().is_some())
+                .flat_map(|d| d.snippets())
+                .any(|s| contains_word(s, &super_name)),
+            ..Default::default()
+        };
+
+        let hook_argument_count = if command.inputs().any(|i| i.variadic()) {
+            None
+        } else {
+            Some(command.inputs().count())
+        };
+        for attached in manager.attached_to(name) {
+            result.calls.push(Call {
+                callee: attached.clone(),
+                argument_count: hook_argument_count,
+                arguments: command
+                    .inputs()
+                    .map(|i| Argument::Variable(i.name().to_string()))
+                    .collect(),
+                is_dependency: false,
+            });
+        }
+
+        for (definition, script) in definitions
+            .iter()
+            .flat_map(|d| d.scripts().map(move |s| (d, s)))
+        {
+            let script = parse(script);
+
+            for command in script.commands {
+                let mut words = command.words.into_iter();
+                let Some(first) = words.next() else {
+                    continue;
+                };
+                let arguments: Vec<Word> = words.collect();
+
+                if first.text == super_name && definition.overwritten().is_some() {
+                    continue;
+                }
+                if first.text == "eval" || computes_command_name(&first.text) {
+                    result.is_opaque = true;
+                    continue;
+                }
+
+                let Ok(name) = CommandName::parse_value(&first.text) else {
+                    continue;
+                };
+
+                if manager.command(&name).is_ok() {
+                    let argument_count = if arguments.iter().any(|a| a.is_splitting) {
+                        None
+                    } else {
+                        Some(arguments.len())
+                    };
+                    result.calls.push(Call {
+                        callee: name,
+                        argument_count,
+                        arguments: arguments.iter().map(Argument::new).collect(),
+                        is_dependency: false,
+                    });
+                } else if first.text == "add_dependency" {
+                    if let Some(dependency) = arguments.get(1) {
+                        if let Ok(callee) = CommandName::parse_value(&dependency.text) {
+                            if manager.command(&callee).is_ok() {
+                                result.calls.push(Call {
+                                    callee,
+                                    argument_count: Some(0),
+                                    arguments: vec![],
+                                    is_dependency: true,
+                                });
+                            } else if !dependency.is_splitting {
+                                result.unknown.insert(dependency.text.clone());
+                            }
+                        }
+                    }
+                } else if first.text.contains('_')
+                    && !header.contains(&first.text)
+                    && !script.functions.contains(&first.text)
+                {
+                    result.unknown.insert(first.text);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// The graph of calls between all `Command`s known to a `CommandManager`
+#[derive(Clone, Debug)]
+pub struct CallGraph {
+    calls: BTreeMap<CommandName, CommandCalls>,
+}
+
+/// The number of arguments `command` expects, if `count` is not in that range
+fn unexpected_argument_count(command: &Command, count: usize) -> Option<String> {
+    let (min, max) = command.argument_range();
+    if count < min || max.is_some_and(|max| count > max) {
+        Some(match max {
+            Some(max) if max == min => format!("{min}"),
+            Some(max) => format!("{min} to {max}"),
+            None => format!("at least {min}"),
+        })
+    } else {
+        None
+    }
+}
+
+impl CallGraph {
+    pub fn new(manager: &CommandManager) -> Self {
+        let header = header_functions();
+        Self {
+            calls: manager
+                .commands()
+                .map(|(name, command)| {
+                    (
+                        name.clone(),
+                        CommandCalls::new(name, command, manager, &header),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// The calls made by `name`
+    pub fn calls(&self, name: &CommandName) -> Option<&CommandCalls> {
+        self.calls.get(name)
+    }
+
+    /// The `Command`s directly called by `name`
+    pub fn callees(&self, name: &CommandName) -> BTreeSet<CommandName> {
+        self.calls
+            .get(name)
+            .map(|c| c.calls.iter().map(|c| c.callee.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Can all calls of `Command`s reachable from `start` be resolved
+    /// statically?
+    pub fn is_transparent_from(&self, start: &CommandName) -> bool {
+        self.reachable_from(start)
+            .iter()
+            .all(|n| self.calls.get(n).map_or(true, |c| !c.is_opaque))
+    }
+
+    /// All `Command`s reachable from `start`, including `start` itself
+    pub fn reachable_from(&self, start: &CommandName) -> BTreeSet<CommandName> {
+        let mut result = BTreeSet::new();
+        let mut to_visit = vec![start.clone()];
+
+        while let Some(name) = to_visit.pop() {
+            if result.insert(name.clone()) {
+                to_visit.extend(self.callees(&name));
+            }
+        }
+
+        result
+    }
+
+    /// The values passed as the `index`th argument to `command` from
+    /// anywhere reachable from `start`
+    ///
+    /// Values passed on from inputs of the calling `Command` are followed.
+    /// Returns `None` if any of the values is not statically known.
+    pub fn argument_values(
+        &self,
+        manager: &CommandManager,
+        start: &CommandName,
+        command: &CommandName,
+        index: usize,
+    ) -> Option<BTreeSet<String>> {
+        let reachable = self.reachable_from(start);
+        self.argument_values_impl(manager, &reachable, command, index, &mut BTreeSet::new())
+    }
+
+    fn argument_values_impl(
+        &self,
+        manager: &CommandManager,
+        reachable: &BTreeSet<CommandName>,
+        command: &CommandName,
+        index: usize,
+        visited: &mut BTreeSet<(CommandName, usize)>,
+    ) -> Option<BTreeSet<String>> {
+        let mut result = BTreeSet::new();
+        if !visited.insert((command.clone(), index)) {
+            return Some(result);
+        }
+
+        let default = manager
+            .command(command)
+            .ok()
+            .and_then(|c| c.inputs().nth(index))
+            .and_then(|i| i.default_value())
+            .map(|d| d.to_string());
+
+        for caller in reachable {
+            let Some(calls) = self.calls.get(caller) else {
+                continue;
+            };
+            for call in calls
+                .calls
+                .iter()
+                .filter(|c| &c.callee == command && !c.is_dependency)
+            {
+                match call.arguments.get(index) {
+                    Some(Argument::Literal(value)) => {
+                        result.insert(value.clone());
+                    }
+                    Some(Argument::Variable(variable)) => {
+                        let input = manager
+                            .command(caller)
+                            .ok()?
+                            .inputs()
+                            .position(|i| i.name().to_string() == *variable && !i.variadic())?;
+                        result.extend(
+                            self.argument_values_impl(manager, reachable, caller, input, visited)?,
+                        );
+                    }
+                    Some(Argument::Unknown) => return None,
+                    None => {
+                        result.insert(default.clone()?);
+                    }
+                }
+            }
+        }
+
+        Some(result)
+    }
+
+    /// The distributions `start` builds, if that is statically known
+    ///
+    /// This is the argument passed to the `_distribution` command.
+    pub fn distributions(
+        &self,
+        manager: &CommandManager,
+        start: &CommandName,
+    ) -> Option<BTreeSet<crate::Distributions>> {
+        let distribution = CommandName::parse_value(DISTRIBUTION_COMMAND).ok()?;
+        self.argument_values(manager, start, &distribution, 0)?
+            .iter()
+            .map(|v| clap::ValueEnum::from_str(v, false).ok())
+            .collect()
+    }
+
+    fn distribution_issues(
+        &self,
+        manager: &CommandManager,
+        start: &CommandName,
+        scope: &BTreeSet<CommandName>,
+    ) -> Vec<Issue> {
+        let Some(distributions) = self.distributions(manager, start) else {
+            // Only matters if some command is restricted to distributions
+            let restricted = scope
+                .iter()
+                .filter(|name| {
+                    manager
+                        .command(name)
+                        .is_ok_and(|c| !c.distributions().is_empty())
+                })
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>();
+            if restricted.is_empty() {
+                return vec![];
+            }
+            return vec![Issue {
+                severity: Severity::Warning,
+                command: start.clone(),
+                message: format!(
+                    "Can not determine the distribution it builds, so the distributions supported by {} are not checked",
+                    restricted.join(", ")
+                ),
+            }];
+        };
+
+        let mut result = vec![];
+        for name in scope {
+            let Ok(command) = manager.command(name) else {
+                continue;
+            };
+            for d in distributions
+                .iter()
+                .filter(|d| !command.supports_distribution(d))
+            {
+                result.push(Issue {
+                    severity: Severity::Error,
+                    command: name.clone(),
+                    message: format!("Does not support distribution {d} used by {start}"),
+                });
+            }
+        }
+        result
+    }
+
+    fn find_cycles(
+        &self,
+        name: &CommandName,
+        stack: &mut Vec<CommandName>,
+        done: &mut BTreeSet<CommandName>,
+        cycles: &mut BTreeSet<Vec<CommandName>>,
+    ) {
+        if let Some(pos) = stack.iter().position(|n| n == name) {
+            let mut cycle = stack[pos..].to_vec();
+            let min = cycle
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, n)| *n)
+                .map(|(i, _)| i)
+                .unwrap_or_default();
+            cycle.rotate_left(min);
+            cycles.insert(cycle);
+            return;
+        }
+        if done.contains(name) {
+            return;
+        }
+
+        stack.push(name.clone());
+        for callee in self.callees(name) {
+            self.find_cycles(&callee, stack, done, cycles);
+        }
+        stack.pop();
+        done.insert(name.clone());
+    }
+
+    /// Check the calls of all `Command`s reachable from `start_command`
+    ///
+    /// All `Command`s are checked if no `start_command` is given. In that
+    /// case internal `Command`s (starting with `_`) that are not called
+    /// by anything are reported, too. `start_command` gets started with
+    /// `arguments`.
+    pub fn issues(
+        &self,
+        manager: &CommandManager,
+        start_command: Option<&CommandName>,
+        arguments: &[String],
+    ) -> Vec<Issue> {
+        let mut result = vec![];
+
+        let scope: BTreeSet<CommandName> = match start_command {
+            Some(start) => {
+                if manager.command(start).is_err() {
+                    return vec![Issue {
+                        severity: Severity::Error,
+                        command: start.clone(),
+                        message: "Command not found".to_string(),
+                    }];
+                }
+                self.reachable_from(start)
+            }
+            None => self.calls.keys().cloned().collect(),
+        };
+
+        let expects_no_arguments = |name: &CommandName| {
+            manager
+                .command(name)
+                .map(|c| c.argument_range().0 == 0)
+                .unwrap_or(true)
+        };
+
+        if let Some(start) = start_command {
+            result.extend(self.distribution_issues(manager, start, &scope));
+
+            let count = arguments.len();
+            if count == 0 && !expects_no_arguments(start) {
+                result.push(Issue {
+                    severity: Severity::Error,
+                    command: start.clone(),
+                    message: "Can not be used as start command: It expects arguments".to_string(),
+                });
+            } else if let Some(expected) = manager
+                .command(start)
+                .ok()
+                .and_then(|c| unexpected_argument_count(c, count))
+            {
+                result.push(Issue {
+                    severity: Severity::Error,
+                    command: start.clone(),
+                    message: format!("Started with {count} argument(s), but it expects {expected}"),
+                });
+            }
+        }
+
+        for name in &scope {
+            let Some(calls) = self.calls.get(name) else {
+                continue;
+            };
+
+            if let Some(hook) = manager.command(name).ok().and_then(|c| c.attach_to()) {
+                if manager.command(hook).is_err() {
+                    result.push(Issue {
+                        severity: Severity::Error,
+                        command: name.clone(),
+                        message: format!("Attached to unknown command {hook}"),
+                    });
+                }
+            }
+
+            for unknown in &calls.unknown {
+                result.push(Issue {
+                    severity: Severity::Error,
+                    command: name.clone(),
+                    message: format!("Calls unknown command {unknown}"),
+                });
+            }
+
+            if calls.calls_super_from_interpreter {
+                result.push(Issue {
+                    severity: Severity::Error,
+                    command: name.clone(),
+                    message: format!(
+                        "Calls super_{name} from a body run by an interpreter, which can not call commands"
+                    ),
+                });
+            }
+
+            for call in &calls.calls {
+                let Ok(callee) = manager.command(&call.callee) else {
+                    continue;
+                };
+                if call.is_dependency {
+                    if !expects_no_arguments(&call.callee) {
+                        result.push(Issue {
+                            severity: Severity::Error,
+                            command: name.clone(),
+                            message: format!(
+                                "Uses {} as a dependency, but it expects arguments",
+                                call.callee
+                            ),
+                        });
+                    }
+                    continue;
+                }
+                let Some(count) = call.argument_count else {
+                    continue;
+                };
+                if let Some(expected) = unexpected_argument_count(callee, count) {
+                    result.push(Issue {
+                        severity: Severity::Error,
+                        command: name.clone(),
+                        message: format!(
+                            "Calls {} with {count} argument(s), but it expects {expected}",
+                            call.callee
+                        ),
+                    });
+                }
+            }
+        }
+
+        let mut cycles = BTreeSet::new();
+        let mut done = BTreeSet::new();
+        for name in &scope {
+            self.find_cycles(name, &mut vec![], &mut done, &mut cycles);
+        }
+        for cycle in cycles {
+            let path = cycle
+                .iter()
+                .chain(cycle.first())
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            result.push(Issue {
+                severity: Severity::Error,
+                command: cycle[0].clone(),
+                message: format!("Recursion detected: {path}"),
+            });
+        }
+
+        // Opaque commands might call any command
+        if start_command.is_none() && !self.calls.values().any(|c| c.is_opaque) {
+            let called: BTreeSet<CommandName> = self
+                .calls
+                .values()
+                .flat_map(|c| c.calls.iter().map(|c| c.callee.clone()))
+                .collect();
+            for name in self.calls.keys() {
+                if name.to_string().starts_with('_') && !called.contains(name) {
+                    result.push(Issue {
+                        severity: Severity::Warning,
+                        command: name.clone(),
+                        message: "Internal command is not used by any other command".to_string(),
+                    });
+                }
+            }
+        }
+
+        result.sort();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandManagerBuilder;
+
+    fn words(script: &str) -> Vec<Vec<String>> {
+        scan(script)
+            .commands
+            .into_iter()
+            .map(|c| c.into_iter().map(|w| w.text).collect())
+            .collect()
+    }
+
+    fn manager(commands: &[(&str, &str)]) -> CommandManager {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, contents) in commands {
+            std::fs::write(dir.path().join(format!("{name}.toml")), contents).unwrap();
+        }
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        builder.build()
+    }
+
+    fn errors(manager: &CommandManager, start: Option<&str>) -> Vec<String> {
+        let start = start.map(|s| CommandName::parse_value(s).unwrap());
+        manager
+            .check_calls(start.as_ref(), &[])
+            .into_iter()
+            .filter(|i| i.severity == Severity::Error)
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_computes_command_name() {
+        assert!(computes_command_name("_distribution_${id}"));
+        assert!(computes_command_name("pkg_$(echo kernel)"));
+        assert!(computes_command_name("${prefix}_base"));
+        assert!(!computes_command_name("${BUSYBOX}"));
+        assert!(!computes_command_name("${ROOT}/usr/bin/foo"));
+        assert!(!computes_command_name("_distribution"));
+    }
+
+    #[test]
+    fn test_scan_quotes_and_separators() {
+        assert_eq!(
+            words("foo 'a b' \"c d\"; bar | baz && qux $(quux x)"),
+            vec![
+                vec!["foo", "a b", "c d"],
+                vec!["bar"],
+                vec!["baz"],
+                vec!["qux"],
+                vec!["quux", "x"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_comments_redirects_heredocs() {
+        let scanner =
+            scan("# not_a_command\nfoo 2>/dev/null\ncat <<EOF > /tmp/x\nbar_baz\nEOF\nqux");
+        let commands: Vec<Vec<(String, bool)>> = scanner
+            .commands
+            .into_iter()
+            .map(|c| c.into_iter().map(|w| (w.text, w.is_redirect)).collect())
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                vec![("foo".to_string(), false), ("/dev/null".to_string(), true)],
+                vec![("cat".to_string(), false), ("/tmp/x".to_string(), true)],
+                vec![("qux".to_string(), false)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_variables() {
+        let script = parse(
+            "echo \"${A}\" $B\n[ -n \"${C}\" ] && cat <<EOF\n${D}\nEOF\nFOO=\"$(echo ${E})\"",
+        );
+        let variables: Vec<Vec<&str>> = script
+            .commands
+            .iter()
+            .map(|c| c.variables.iter().map(|v| v.as_str()).collect())
+            .collect();
+        assert_eq!(
+            variables,
+            vec![vec!["A", "B"], vec![], vec!["D"], vec!["E"]]
+        );
+        assert_eq!(
+            script.variables.iter().collect::<Vec<_>>(),
+            vec!["A", "B", "C", "D", "E"]
+        );
+    }
+
+    #[test]
+    fn test_scan_function_definitions() {
+        let scanner = scan("my_helper() {\n    echo\n}\nmy_helper");
+        assert_eq!(scanner.functions, vec!["my_helper".to_string()]);
+        assert_eq!(
+            words("my_helper() {\n    echo\n}"),
+            vec![vec!["{"], vec!["echo"], vec!["}"]]
+        );
+    }
+
+    #[test]
+    fn test_builtin_commands_have_no_errors() {
+        let manager = CommandManagerBuilder::default().build();
+        assert_eq!(errors(&manager, None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let manager = manager(&[(
+            "test_cmd",
+            "[command]\nhelp = \"Test\"\nscript = \"\"\"\nif true; then\n    some_typo foo\nfi\n\"\"\"\n",
+        )]);
+        assert_eq!(
+            errors(&manager, Some("test_cmd")),
+            vec!["error: test_cmd: Calls unknown command some_typo".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_argument_count() {
+        let manager = manager(&[
+            (
+                "test_callee",
+                "[command]\nhelp = \"Test\"\ninputs = [\"a\", { name = \"b\", optional = true }]\n",
+            ),
+            (
+                "test_cmd",
+                "[command]\nhelp = \"Test\"\nscript = \"\"\"\ntest_callee\ntest_callee 1 \\\"2\\\"\ntest_callee 1 2 3\ntest_callee ${FOO}\n\"\"\"\n",
+            ),
+        ]);
+        assert_eq!(
+            errors(&manager, Some("test_cmd")),
+            vec![
+                "error: test_cmd: Calls test_callee with 0 argument(s), but it expects 1 to 2"
+                    .to_string(),
+                "error: test_cmd: Calls test_callee with 3 argument(s), but it expects 1 to 2"
+                    .to_string(),
+            ]
+        );
+        assert_eq!(
+            errors(&manager, Some("test_callee")),
+            vec![
+                "error: test_callee: Can not be used as start command: It expects arguments"
+                    .to_string()
+            ]
+        );
+
+        let start = CommandName::parse_value("test_callee").unwrap();
+        assert!(manager
+            .check_calls(Some(&start), &["1".to_string(), "2".to_string()])
+            .is_empty());
+        assert_eq!(
+            manager
+                .check_calls(
+                    Some(&start),
+                    &["1".to_string(), "2".to_string(), "3".to_string()]
+                )
+                .into_iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "error: test_callee: Started with 3 argument(s), but it expects 1 to 2".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        let manager = manager(&[
+            (
+                "test_a",
+                "[command]\nhelp = \"Test\"\nscript = \"test_b\"\n",
+            ),
+            (
+                "test_b",
+                "[command]\nhelp = \"Test\"\nscript = \"add_dependency X test_a\"\n",
+            ),
+        ]);
+        assert_eq!(
+            errors(&manager, Some("test_b")),
+            vec!["error: test_a: Recursion detected: test_a -> test_b -> test_a".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_unused_internal_command() {
+        let manager = manager(&[("_test_unused", "[command]\nhelp = \"Test\"\n")]);
+        let issues = manager.check_calls(None, &[]);
+        assert!(issues
+            .iter()
+            .any(|i| i.severity == Severity::Warning && i.command.to_string() == "_test_unused"));
+        assert!(manager
+            .check_calls(
+                Some(&CommandName::parse_value("_test_unused").unwrap()),
+                &[]
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn test_opaque_calls() {
+        let manager = manager(&[
+            ("_test_target_a", "[command]\nhelp = \"Test\"\n"),
+            (
+                "test_dynamic",
+                "[command]\nhelp = \"Test\"\nscript = \"id=a\\n\\\"_test_target_${id}\\\"\"\n",
+            ),
+            (
+                "test_python",
+                "[command]\nhelp = \"Test\"\ninterpreter = \"/usr/bin/python3\"\nscript = \"print()\"\n",
+            ),
+            ("test_plain", "[command]\nhelp = \"Test\"\nscript = \"_test_target_a\"\n"),
+        ]);
+        let graph = manager.call_graph();
+        let is_opaque = |name: &str| {
+            graph
+                .calls(&CommandName::parse_value(name).unwrap())
+                .unwrap()
+                .is_opaque
+        };
+        assert!(is_opaque("test_dynamic"));
+        assert!(is_opaque("test_python"));
+        assert!(!is_opaque("test_plain"));
+        assert!(!graph.is_transparent_from(&CommandName::parse_value("test_dynamic").unwrap()));
+        assert!(graph.is_transparent_from(&CommandName::parse_value("test_plain").unwrap()));
+
+        // The dynamic call is neither an unknown command nor leaves
+        // _test_target_a unused:
+        assert!(errors(&manager, Some("test_dynamic")).is_empty());
+        assert!(!manager
+            .check_calls(None, &[])
+            .iter()
+            .any(|i| i.command.to_string() == "_test_target_a"));
+    }
+
+    #[test]
+    fn test_super_from_interpreter() {
+        let calling = manager(&[(
+            "hook_write_pacman_conf",
+            "[command]\ninterpreter = \"/usr/bin/python3\"\nscript = \"subprocess.run(['super_hook_write_pacman_conf'])\"\n",
+        )]);
+        assert_eq!(
+            errors(&calling, Some("hook_write_pacman_conf")),
+            vec!["error: hook_write_pacman_conf: Calls super_hook_write_pacman_conf from a body run by an interpreter, which can not call commands".to_string()]
+        );
+
+        let other = manager(&[(
+            "hook_write_pacman_conf",
+            "[command]\ninterpreter = \"/usr/bin/python3\"\nscript = \"print('super_hook_write_pacman_conf_x')\"\n",
+        )]);
+        assert!(errors(&other, Some("hook_write_pacman_conf")).is_empty());
+    }
+
+    #[test]
+    fn test_attachments() {
+        let manager = manager(&[
+            (
+                "test_hook",
+                "[command]\nhelp = \"Test\"\ninputs = [\"a\"]\n",
+            ),
+            (
+                "test_attached",
+                "[command]\nhelp = \"Test\"\nattach_to = \"test_hook\"\n",
+            ),
+            (
+                "test_lost",
+                "[command]\nhelp = \"Test\"\nattach_to = \"test_missing\"\n",
+            ),
+        ]);
+        assert_eq!(
+            errors(&manager, None),
+            vec![
+                "error: test_hook: Calls test_attached with 1 argument(s), but it expects 0"
+                    .to_string(),
+                "error: test_lost: Attached to unknown command test_missing".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_distributions() {
+        let manager = manager(&[
+            (
+                "test_serpentos",
+                "[command]\nhelp = \"Test\"\nscript = \"define_system serpentos a b c\\npkg_kernel\"\n",
+            ),
+            (
+                "test_arch",
+                "[command]\nhelp = \"Test\"\nscript = \"define_system arch a b c\\npkg_kernel\"\n",
+            ),
+        ]);
+        let graph = manager.call_graph();
+        let start = CommandName::parse_value("test_serpentos").unwrap();
+        assert_eq!(
+            graph.distributions(&manager, &start),
+            Some(BTreeSet::from([crate::Distributions::SerpentOS]))
+        );
+        assert_eq!(
+            errors(&manager, Some("test_serpentos")),
+            vec![
+                "error: pkg_kernel: Does not support distribution serpentos used by test_serpentos"
+                    .to_string()
+            ]
+        );
+        assert!(errors(&manager, Some("test_arch")).is_empty());
+    }
+
+    #[test]
+    fn test_distributions_unknown() {
+        let manager = manager(&[(
+            "test_computed",
+            "[command]\nhelp = \"Test\"\nscript = \"define_system \\\"$(cat /etc/distribution)\\\" a b c\\npkg_kernel\"\n",
+        )]);
+        let start = CommandName::parse_value("test_computed").unwrap();
+        assert_eq!(manager.call_graph().distributions(&manager, &start), None);
+        assert_eq!(
+            manager
+                .check_calls(Some(&start), &[])
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>(),
+            vec!["warning: test_computed: Can not determine the distribution it builds, so the distributions supported by pkg_kernel are not checked".to_string()]
+        );
+    }
+}
@@ -0,0 +1,800 @@
+// Copyright © Tobias Hunger <tobias.hunger@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Lints for `Command` definitions
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::call_graph::{Issue, Severity};
+use crate::commands::{Command, CommandManager, CommandName};
+use crate::Phases;
+
+/// Environment variables set up by the agent runner
+const RUNNER_VARIABLES: [&str; 5] = [
+    "CLRM_CONTAINER",
+    "CURRENT_PHASE",
+    "PHASE_IS_NETWORKED",
+    "ROOT_FS",
+    "PATH",
+];
+
+/// The variables set by `script` for its own use
+fn local_variables(script: &crate::call_graph::Script) -> BTreeSet<String> {
+    let mut result = BTreeSet::new();
+
+    for command in &script.commands {
+        result.extend(command.assignments.iter().cloned());
+
+        let Some((first, arguments)) = command.words.split_first() else {
+            continue;
+        };
+        let names = arguments
+            .iter()
+            .map(|w| w.text.as_str())
+            .filter(|w| !w.starts_with('-'));
+        match first.text.as_str() {
+            "for" | "add_dependency" | "export_constant" => {
+                result.extend(names.take(1).map(|n| n.to_string()));
+            }
+            "read" => result.extend(names.map(|n| n.to_string())),
+            "local" | "export" | "readonly" => result.extend(names.map(|n| {
+                crate::call_graph::assigned_variable(n)
+                    .unwrap_or(n)
+                    .to_string()
+            })),
+            _ => {}
+        }
+    }
+
+    result
+}
+
+/// The variable exported by `command` and the verb used for that
+fn exported_variable(command: &crate::call_graph::SimpleCommand) -> Option<(&'static str, String)> {
+    match command.words.as_slice() {
+        [verb, name, ..] => ["export_constant", "export_var", "append_var"]
+            .into_iter()
+            .find(|v| *v == verb.text)
+            .map(|v| (v, name.text.clone())),
+        _ => None,
+    }
+}
+
+/// The variables exported by `script` via `export_constant`, `export_var`
+/// or `append_var`
+pub(crate) fn exported_variables(
+    script: &crate::call_graph::Script,
+) -> Vec<(&'static str, String)> {
+    script
+        .commands
+        .iter()
+        .filter_map(exported_variable)
+        .collect()
+}
+
+/// The script snippets of `command` together with the phases they run in
+///
+/// The common script runs in all phases.
+pub(crate) fn phase_scripts(command: &Command) -> Vec<(Vec<Phases>, &str)> {
+    if command.interpreter().is_some() {
+        return vec![];
+    }
+    let phases = Phases::iter()
+        .filter(|p| command.runs_in_phase(p))
+        .cloned()
+        .collect();
+    std::iter::once((phases, command.script.as_str()))
+        .chain(command.phases().flat_map(|p| {
+            let ps = command.phase_script(p).expect("Phase is known");
+            [
+                (vec![p.clone()], ps.setup.as_str()),
+                (vec![p.clone()], ps.script.as_str()),
+            ]
+        }))
+        .filter(|(p, s)| !p.is_empty() && !s.trim().is_empty())
+        .collect()
+}
+
+/// The script snippets `command` runs in `phase`, in the order they run in
+fn phase_snippets<'a>(command: &'a Command, phase: &Phases) -> Vec<&'a str> {
+    if command.interpreter().is_some() || !command.runs_in_phase(phase) {
+        return vec![];
+    }
+    let phase_script = command.phase_script(phase);
+    [
+        phase_script.map(|ps| ps.setup.as_str()),
+        Some(command.script.as_str()),
+        phase_script.map(|ps| ps.script.as_str()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn phase_index(phase: &Phases) -> usize {
+    Phases::iter()
+        .position(|p| p == phase)
+        .expect("Phase is known")
+}
+
+/// Variables that are available in every phase without a command exporting them
+fn builtin_variables() -> BTreeSet<String> {
+    let header = crate::call_graph::parse(&crate::scripts::header_script());
+    local_variables(&header)
+        .into_iter()
+        .chain(
+            crate::context::SCRIPT_VARIABLES
+                .iter()
+                .map(|v| v.to_string()),
+        )
+        .chain(RUNNER_VARIABLES.iter().map(|v| v.to_string()))
+        .chain(Phases::iter().map(|p| format!("PHASE_{}", p.to_string().to_uppercase())))
+        .collect()
+}
+
+fn issue(severity: Severity, command: &CommandName, message: String) -> Issue {
+    Issue {
+        severity,
+        command: command.clone(),
+        message,
+    }
+}
+
+/// The variables `command` sets up for itself
+fn known_variables(command: &Command) -> BTreeSet<String> {
+    phase_scripts(command)
+        .iter()
+        .flat_map(|(_, s)| local_variables(&crate::call_graph::parse(s)))
+        .chain(command.inputs().map(|i| i.name().to_string()))
+        .chain(
+            command
+                .data_directory()
+                .map(|_| "COMMAND_DATA_DIR".to_string()),
+        )
+        .collect()
+}
+
+/// The commands builds start with: Those no other command calls
+///
+/// Commands used as dependencies start builds of their own.
+fn start_commands(manager: &CommandManager) -> Vec<CommandName> {
+    let graph = manager.call_graph();
+    let called: BTreeSet<&CommandName> = manager
+        .commands()
+        .filter_map(|(name, _)| graph.calls(name))
+        .flat_map(|c| c.calls.iter().filter(|c| !c.is_dependency))
+        .map(|c| &c.callee)
+        .collect();
+    let mut result: Vec<CommandName> = manager
+        .commands()
+        .map(|(name, _)| name)
+        .filter(|name| !called.contains(name))
+        .cloned()
+        .collect();
+
+    // Commands calling each other in a cycle are not reached otherwise
+    let mut reachable: BTreeSet<CommandName> = result
+        .iter()
+        .flat_map(|s| graph.reachable_from(s))
+        .collect();
+    for (name, _) in manager.commands() {
+        if !reachable.contains(name) {
+            reachable.extend(graph.reachable_from(name));
+            result.push(name.clone());
+        }
+    }
+    result
+}
+
+/// A variable used by a command
+struct Use {
+    command: CommandName,
+    phase: Phases,
+    variable: String,
+    /// Some command exported the variable before it got used
+    is_exported: bool,
+}
+
+/// The branches of `if` statements leading to a command in a build: The
+/// address of the snippet, the number of the `if` statement in it and the
+/// branch taken
+///
+/// Snippets are identified by address, as the same snippet runs in several
+/// phases and is assumed to take the same branches in all of them.
+type BranchPath = Vec<(usize, usize, usize)>;
+
+/// Can the commands at the end of `a` and `b` never both run in one build?
+fn are_alternatives(a: &BranchPath, b: &BranchPath) -> bool {
+    a.iter()
+        .zip(b)
+        .find(|(a, b)| a != b)
+        .is_some_and(|(a, b)| (a.0, a.1) == (b.0, b.1))
+}
+
+/// Follows one build through all phases, in the order its commands run in
+struct Build<'a> {
+    manager: &'a CommandManager,
+    builtin: &'a BTreeSet<String>,
+    exported: BTreeSet<String>,
+    /// Variable -> the first command exporting it
+    exporters: BTreeMap<String, CommandName>,
+    /// Constant -> all commands exporting it and the branches they do so in
+    constants: BTreeMap<String, Vec<(CommandName, BranchPath)>>,
+    uses: Vec<Use>,
+    stack: Vec<(CommandName, usize)>,
+    /// The branches taken by the callers of the running command
+    branches: BranchPath,
+}
+
+impl<'a> Build<'a> {
+    fn new(
+        manager: &'a CommandManager,
+        builtin: &'a BTreeSet<String>,
+        start: &CommandName,
+    ) -> Self {
+        let mut result = Self {
+            manager,
+            builtin,
+            exported: BTreeSet::new(),
+            exporters: BTreeMap::new(),
+            constants: BTreeMap::new(),
+            uses: vec![],
+            stack: vec![],
+            branches: vec![],
+        };
+        if let Ok(command) = manager.command(start) {
+            for phase in Phases::iter() {
+                result.run(start, command, 0, phase);
+            }
+        }
+        result
+    }
+
+    /// Run the definition of `name` shadowed `depth` times in `phase`
+    fn run(&mut self, name: &CommandName, command: &Command, depth: usize, phase: &Phases) {
+        let frame = (name.clone(), depth);
+        if self.stack.contains(&frame) {
+            // Recursion is reported elsewhere
+            return;
+        }
+        self.stack.push(frame);
+
+        let manager = self.manager;
+        let known = known_variables(command);
+        let super_name = format!("super_{name}");
+        for snippet in phase_snippets(command, phase) {
+            let snippet_address = snippet.as_ptr() as usize;
+            for c in crate::call_graph::parse(snippet).commands {
+                let caller_branches = self.branches.len();
+                self.branches
+                    .extend(c.branches.iter().map(|(i, b)| (snippet_address, *i, *b)));
+
+                for variable in &c.variables {
+                    if !self.builtin.contains(variable) && !known.contains(variable) {
+                        self.uses.push(Use {
+                            command: name.clone(),
+                            phase: phase.clone(),
+                            variable: variable.clone(),
+                            is_exported: self.exported.contains(variable),
+                        });
+                    }
+                }
+
+                if let Some((verb, variable)) = exported_variable(&c) {
+                    self.exporters
+                        .entry(variable.clone())
+                        .or_insert_with(|| name.clone());
+                    if verb == "export_constant" {
+                        self.constants
+                            .entry(variable.clone())
+                            .or_default()
+                            .push((name.clone(), self.branches.clone()));
+                    }
+                    self.exported.insert(variable);
+                } else if let Some(first) = c.words.first() {
+                    if first.text == super_name {
+                        if let Some(overwritten) = command.overwritten() {
+                            self.run(name, overwritten, depth + 1, phase);
+                        }
+                    } else if let Ok(callee) = CommandName::parse_value(&first.text) {
+                        if let Ok(callee_command) = manager.command(&callee) {
+                            self.run(&callee, callee_command, 0, phase);
+                        }
+                    }
+                }
+                self.branches.truncate(caller_branches);
+            }
+        }
+        if depth == 0 {
+            for attached in manager.attached_to(name) {
+                if let Ok(attached_command) = manager.command(attached) {
+                    self.run(attached, attached_command, 0, phase);
+                }
+            }
+        }
+
+        self.stack.pop();
+    }
+}
+
+/// Check the order variables get exported and used in, following the
+/// builds of all start commands through all phases
+fn lint_builds(manager: &CommandManager, builtin: &BTreeSet<String>) -> BTreeSet<Issue> {
+    let mut result = BTreeSet::new();
+
+    // variable name -> first phase any command exports it in
+    let mut exported = BTreeMap::<String, usize>::new();
+    for (_, command) in manager.commands() {
+        for (phases, script) in phase_scripts(command) {
+            let first_phase = phases.iter().map(phase_index).min().unwrap_or_default();
+            for (_, variable) in exported_variables(&crate::call_graph::parse(script)) {
+                let entry = exported.entry(variable).or_insert(first_phase);
+                *entry = (*entry).min(first_phase);
+            }
+        }
+    }
+
+    for start in start_commands(manager) {
+        let build = Build::new(manager, builtin, &start);
+
+        for (variable, exports) in &build.constants {
+            // Commands exporting the constant in the same run of the build
+            let commands: BTreeSet<&CommandName> = exports
+                .iter()
+                .filter(|(name, branches)| {
+                    exports.iter().any(|(other, other_branches)| {
+                        other != name && !are_alternatives(branches, other_branches)
+                    })
+                })
+                .map(|(name, _)| name)
+                .collect();
+            if commands.len() > 1 {
+                let names = commands
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                for c in commands {
+                    result.insert(issue(
+                        Severity::Warning,
+                        c,
+                        format!("Constant {variable} is exported by several commands: {names}"),
+                    ));
+                }
+            }
+        }
+
+        // Only report the first phase a command uses a variable too early in
+        let mut reported = BTreeSet::new();
+        for u in build.uses.iter().filter(|u| !u.is_exported) {
+            let variable = &u.variable;
+            if !reported.insert((&u.command, variable)) {
+                continue;
+            }
+            let message = if let Some(exporter) = build.exporters.get(variable) {
+                // The build exports the variable itself, but too late
+                format!(
+                    "Uses {variable} in phase {}, before {exporter} exports it",
+                    u.phase
+                )
+            } else {
+                // Some command running before the start command might export it
+                match exported.get(variable) {
+                    Some(p) if *p <= phase_index(&u.phase) => continue,
+                    Some(p) => format!(
+                        "Uses {variable} in phase {}, but it is exported in phase {} only",
+                        u.phase,
+                        Phases::iter().nth(*p).expect("Phase is known"),
+                    ),
+                    None => format!("Uses {variable}, but no command exports it"),
+                }
+            };
+            result.insert(issue(Severity::Error, &u.command, message));
+        }
+    }
+
+    result
+}
+
+/// Lint all commands known to `manager`
+///
+/// This finds unused inputs, constants exported by more than one command
+/// in the same build, tools from `/usr/bin` used unchecked in the bootstrap
+/// environment and variables used before they are exported.
+pub fn lint(manager: &CommandManager) -> Vec<Issue> {
+    let builtin = builtin_variables();
+    let mut result = lint_builds(manager, &builtin);
+
+    for (name, command) in manager.commands() {
+        let scripts = phase_scripts(command);
+        let parsed: Vec<_> = scripts
+            .iter()
+            .map(|(_, s)| crate::call_graph::parse(s))
+            .collect();
+        let referenced: BTreeSet<&String> = parsed.iter().flat_map(|s| &s.variables).collect();
+
+        let uses_arguments = scripts
+            .iter()
+            .any(|(_, s)| s.contains("${@}") || s.contains("$@"));
+        for input in command.inputs().filter(|_| command.interpreter().is_none()) {
+            let used = if input.variadic() {
+                uses_arguments
+            } else {
+                referenced.contains(&input.name().to_string())
+            };
+            if !used {
+                result.insert(issue(
+                    Severity::Warning,
+                    name,
+                    format!("Input {} is declared but never used", input.name()),
+                ));
+            }
+        }
+
+        for input in command.inputs().filter(|i| i.variadic()) {
+            if referenced.contains(&input.name().to_string()) {
+                result.insert(issue(
+                    Severity::Error,
+                    name,
+                    format!(
+                        "Uses variadic input {}, whose values are in \"${{@}}\"",
+                        input.name()
+                    ),
+                ));
+            }
+        }
+
+        let guarded: BTreeSet<&str> = parsed
+            .iter()
+            .flat_map(|s| s.commands.iter())
+            .flat_map(|c| c.words.windows(2))
+            .filter(|w| w[0].text == "-x")
+            .map(|w| w[1].text.as_str())
+            .collect();
+
+        for (phases, parsed) in scripts.iter().map(|(p, _)| p).zip(&parsed) {
+            let bootstrap = phases.iter().any(crate::agent_runner::run_in_bootstrap);
+            let tools: BTreeSet<&str> = parsed
+                .commands
+                .iter()
+                .filter_map(|c| c.words.first())
+                .map(|w| w.text.as_str())
+                .filter(|t| t.starts_with("/usr/bin/") && !guarded.contains(t))
+                .collect();
+            for tool in tools.into_iter().filter(|_| bootstrap) {
+                result.insert(issue(
+                    Severity::Warning,
+                    name,
+                    format!("Uses {tool} in the bootstrap environment without checking it exists"),
+                ));
+            }
+        }
+    }
+
+    result.into_iter().collect()
+}
+
+/// The 1-based line number in an error message of `sh -n`
+///
+/// Shells report errors as `<script>: line <n>: <message>` or as
+/// `<script>: <n>: <message>`.
+fn error_line(message: &str) -> Option<usize> {
+    message.match_indices(": ").find_map(|(pos, _)| {
+        let rest = &message[pos + 2..];
+        let rest = rest.strip_prefix("line ").unwrap_or(rest);
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        if digits > 0 && rest[digits..].starts_with(": ") {
+            rest[..digits].parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Run `busybox sh -n` on the agent script of every start command in every
+/// phase
+///
+/// Errors are reported for the command the broken line came from.
+pub fn syntax_check(manager: &CommandManager, busybox_binary: &Path) -> anyhow::Result<Vec<Issue>> {
+    let ctx = crate::context::ContextBuilder::new(
+        crate::printer::Printer::new(&crate::printer::LogLevel::Off, false),
+        manager.clone(),
+    )
+    .timestamp("19700101.0000".to_string())?
+    .build()?;
+
+    // (command, message) -> phases
+    let mut errors = BTreeMap::<(CommandName, String), Vec<String>>::new();
+    for start in start_commands(manager) {
+        let build_ctx = ctx.unchecked_placeholder_build_context(&start)?;
+        for phase in Phases::iter() {
+            let (script, source_map) =
+                crate::scripts::render_mapped_script(&build_ctx, &start, phase)?;
+
+            let mut file = tempfile::NamedTempFile::new()
+                .context("Failed to create temporary file for syntax check")?;
+            file.write_all(script.as_bytes())
+                .context("Failed to write temporary file for syntax check")?;
+
+            let output = std::process::Command::new(busybox_binary)
+                .arg("sh")
+                .arg("-n")
+                .arg(file.path())
+                .output()
+                .context(format!("Failed to run {busybox_binary:?}"))?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr)
+                    .replace(&file.path().to_string_lossy().to_string(), "agent script");
+                let stderr = stderr.trim().to_string();
+                let location = error_line(&stderr).and_then(|l| source_map.lookup(l));
+                let (command, message) = match location {
+                    Some(l) => (l.command.clone(), format!("{l}: {stderr}")),
+                    None => (start.clone(), stderr),
+                };
+                let phases = errors.entry((command, message)).or_default();
+                if !phases.contains(&phase.to_string()) {
+                    phases.push(phase.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(errors
+        .into_iter()
+        .map(|((command, message), phases)| {
+            issue(
+                Severity::Error,
+                &command,
+                format!("Syntax error in phase(s) {}: {message}", phases.join(", ")),
+            )
+        })
+        .collect())
+}
+
+/// Run all checks on all commands known to `manager`
+pub fn check(manager: &CommandManager, busybox_binary: &Path) -> anyhow::Result<Vec<Issue>> {
+    let mut result = manager.check_calls(None, &[]);
+    result.extend(lint(manager));
+    result.extend(syntax_check(manager, busybox_binary)?);
+
+    result.sort();
+    result.dedup();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandManagerBuilder;
+
+    fn manager(commands: &[(&str, &str)]) -> CommandManager {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, contents) in commands {
+            std::fs::write(dir.path().join(format!("{name}.toml")), contents).unwrap();
+        }
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        builder.build()
+    }
+
+    fn issues_of(issues: Vec<Issue>, command: &str) -> Vec<String> {
+        issues
+            .into_iter()
+            .filter(|i| i.command.to_string() == command)
+            .map(|i| i.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_commands_have_no_lint_errors() {
+        let manager = CommandManagerBuilder::default().build();
+        assert_eq!(lint(&manager), vec![]);
+    }
+
+    #[test]
+    fn test_builtin_lists_stay_lists() {
+        let manager = CommandManagerBuilder::default().build();
+        for (name, command) in manager.commands() {
+            for (_, script) in phase_scripts(command) {
+                for (verb, variable) in exported_variables(&crate::call_graph::parse(script)) {
+                    assert!(
+                        verb == "append_var"
+                            || !["PACKAGES", "ENABLED_SYSTEMD_UNITS", "KERNEL_CMDLINE"]
+                                .contains(&variable.as_str()),
+                        "{name} turns list {variable} into a single value"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unused_input() {
+        let manager = manager(&[(
+            "test_cmd",
+            "[command]\nhelp = \"Test\"\ninputs = [\"used\", \"unused\"]\nscript = \"echo ${used}\"\n",
+        )]);
+        assert_eq!(
+            issues_of(lint(&manager), "test_cmd"),
+            vec!["warning: test_cmd: Input unused is declared but never used".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_variadic_input() {
+        let manager = manager(&[
+            (
+                "test_good",
+                "[command]\nhelp = \"Test\"\ninputs = [{ name = \"items\", variadic = true }]\nscript = \"echo \\\"${@}\\\"\"\n",
+            ),
+            (
+                "test_bad",
+                "[command]\nhelp = \"Test\"\ninputs = [{ name = \"items\", variadic = true }]\nscript = \"echo ${items}\"\n",
+            ),
+        ]);
+        let issues = lint(&manager);
+        assert!(issues_of(issues.clone(), "test_good").is_empty());
+        assert_eq!(
+            issues_of(issues, "test_bad"),
+            vec![
+                "warning: test_bad: Input items is declared but never used".to_string(),
+                "error: test_bad: Uses variadic input items, whose values are in \"${@}\""
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        let manager = manager(&[
+            (
+                "test_export",
+                "[command]\nhelp = \"Test\"\n[command.phases.polish]\nscript = \"export_constant TEST_LATE foo\"\n",
+            ),
+            (
+                "test_cmd",
+                "[command]\nhelp = \"Test\"\nscript = \"\"\"\nlocal_var=1\necho \"${local_var} ${ROOT_FS} $TEST_LATE ${TEST_UNKNOWN}\"\ncat <<'EOF'\n$NOT_EXPANDED\nEOF\n\"\"\"\n",
+            ),
+        ]);
+        assert_eq!(
+            issues_of(lint(&manager), "test_cmd"),
+            vec![
+                "error: test_cmd: Uses TEST_LATE in phase prepare, but it is exported in phase polish only".to_string(),
+                "error: test_cmd: Uses TEST_UNKNOWN, but no command exports it".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_constant() {
+        let manager = manager(&[
+            (
+                "test_a",
+                "[command]\nhelp = \"Test\"\nscript = \"export_constant TEST_CONSTANT a\"\n",
+            ),
+            (
+                "test_b",
+                "[command]\nhelp = \"Test\"\nscript = \"export_constant TEST_CONSTANT b\"\n",
+            ),
+            (
+                "test_c",
+                "[command]\nhelp = \"Test\"\nscript = \"export_constant TEST_OTHER c\"\n",
+            ),
+            (
+                "test_root",
+                "[command]\nhelp = \"Test\"\nscript = \"test_a\\ntest_b\"\n",
+            ),
+            (
+                "test_other_root",
+                "[command]\nhelp = \"Test\"\nscript = \"test_c\"\n",
+            ),
+            (
+                "test_d",
+                "[command]\nhelp = \"Test\"\nscript = \"export_constant TEST_CHOSEN d\"\n",
+            ),
+            (
+                "test_e",
+                "[command]\nhelp = \"Test\"\nscript = \"export_constant TEST_CHOSEN e\"\n",
+            ),
+            (
+                "test_choice_root",
+                "[command]\nhelp = \"Test\"\nscript = \"\"\"\nif [ -n \"${1}\" ]; then\n    test_d\nelse\n    test_e\nfi\n\"\"\"\n",
+            ),
+        ]);
+        let issues = lint(&manager);
+        assert_eq!(
+            issues_of(issues.clone(), "test_a"),
+            vec!["warning: test_a: Constant TEST_CONSTANT is exported by several commands: test_a, test_b".to_string()]
+        );
+        // test_c is never used in the same build as test_a or test_b
+        assert!(issues_of(issues.clone(), "test_c").is_empty());
+        // test_d and test_e run in different branches of the same if
+        assert_eq!(issues_of(issues, "test_d"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_variable_order() {
+        let commands = [
+            (
+                "test_export",
+                "[command]\nhelp = \"Test\"\nscript = \"export_var TEST_VAR 1\"\n",
+            ),
+            (
+                "test_use",
+                "[command]\nhelp = \"Test\"\nscript = \"echo ${TEST_VAR}\"\n",
+            ),
+            (
+                "test_root",
+                "[command]\nhelp = \"Test\"\n[command.phases.prepare]\nscript = \"test_export\"\n[command.phases.install]\nscript = \"test_use\"\n",
+            ),
+        ];
+        assert!(issues_of(lint(&manager(&commands)), "test_use").is_empty());
+
+        let bad_root = (
+            "test_bad_root",
+            "[command]\nhelp = \"Test\"\nscript = \"test_use\\ntest_export\"\n",
+        );
+        let manager = manager(&[&commands[..], &[bad_root]].concat());
+        assert_eq!(
+            issues_of(lint(&manager), "test_use"),
+            vec![
+                "error: test_use: Uses TEST_VAR in phase prepare, before test_export exports it"
+                    .to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bootstrap_tools() {
+        let manager = manager(&[
+            (
+                "test_a",
+                "[command]\nhelp = \"Test\"\n[command.phases.polish]\nscript = \"/usr/bin/foo\"\n[command.phases.install]\nscript = \"/usr/bin/bar\"\n",
+            ),
+            (
+                "test_b",
+                "[command]\nhelp = \"Test\"\nscript = \"\"\"\ntest -x /usr/bin/bar || error \"no bar\"\n/usr/bin/bar\n\"\"\"\n",
+            ),
+        ]);
+        let issues = lint(&manager);
+        assert_eq!(
+            issues_of(issues.clone(), "test_a"),
+            vec!["warning: test_a: Uses /usr/bin/bar in the bootstrap environment without checking it exists".to_string()]
+        );
+        assert!(issues_of(issues, "test_b").is_empty());
+    }
+
+    #[test]
+    fn test_syntax_check() {
+        let manager = manager(&[
+            (
+                "test_good",
+                "[command]\nhelp = \"Test\"\nscript = \"if true; then echo; fi\"\n",
+            ),
+            (
+                "test_bad",
+                "[command]\nhelp = \"Test\"\n[command.phases.test]\nscript = \"if true; then echo\"\n",
+            ),
+        ]);
+
+        // Pretend to be busybox: Drop the `sh` applet name and run the system shell
+        let dir = tempfile::tempdir().unwrap();
+        let busybox = dir.path().join("busybox");
+        std::fs::write(&busybox, "#!/bin/sh\nshift\nexec /bin/sh \"$@\"\n").unwrap();
+        std::fs::set_permissions(
+            &busybox,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+
+        let issues = syntax_check(&manager, &busybox).unwrap();
+        assert!(issues_of(issues.clone(), "test_good").is_empty());
+        let bad = issues_of(issues, "test_bad");
+        assert_eq!(bad.len(), 1);
+        assert!(bad[0].starts_with("error: test_bad: Syntax error in phase(s) test: "));
+    }
+}
@@ -1,7 +1,11 @@
 // Copyright © Tobias Hunger <tobias.hunger@gmail.com>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::{collections::BTreeMap, ffi::OsStr, path::Path};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Context};
 
@@ -41,6 +45,7 @@ fn validate_variable_name(name: &str) -> anyhow::Result<()> {
 }
 
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, serde::Deserialize)]
+#[serde(try_from = "String")]
 pub struct CommandName(String);
 
 impl TryFrom<String> for CommandName {
@@ -97,6 +102,39 @@ impl std::fmt::Display for VariableName {
     }
 }
 
+/// The type of value an `Input` accepts
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    /// Any string
+    #[default]
+    String,
+    /// A boolean: Normalized to `on` or `off`
+    Bool,
+    /// A (signed) integer
+    Integer,
+    /// One out of a list of allowed `values`
+    Enum,
+    /// An absolute path
+    Path,
+    /// A string matching an extended regular expression `pattern`
+    Regex,
+}
+
+impl std::fmt::Display for InputType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            InputType::String => "string",
+            InputType::Bool => "bool",
+            InputType::Integer => "integer",
+            InputType::Enum => "enum",
+            InputType::Path => "path",
+            InputType::Regex => "regex",
+        };
+        write!(f, "{name}")
+    }
+}
+
 /// Meta-information about an `Input`
 #[derive(Clone, Debug, serde::Deserialize)]
 #[serde(untagged)]
@@ -107,6 +145,14 @@ pub enum Input {
         help: Option<String>,
         #[serde(default)]
         optional: bool,
+        #[serde(default, rename = "type")]
+        input_type: InputType,
+        #[serde(default)]
+        values: Vec<String>,
+        pattern: Option<String>,
+        default: Option<String>,
+        #[serde(default, alias = "rest")]
+        variadic: bool,
     },
 }
 
@@ -128,7 +174,246 @@ impl Input {
     pub fn optional(&self) -> bool {
         match self {
             Input::Basic(_) => false,
-            Input::Full { optional, .. } => *optional,
+            Input::Full {
+                optional, default, ..
+            } => *optional || default.is_some(),
+        }
+    }
+
+    pub fn default_value(&self) -> Option<&str> {
+        match self {
+            Input::Basic(_) => None,
+            Input::Full { default, .. } => default.as_deref(),
+        }
+    }
+
+    /// A variadic `Input` takes all remaining arguments
+    ///
+    /// Its values are not bound to a variable, but stay in `"${@}"`.
+    pub fn variadic(&self) -> bool {
+        match self {
+            Input::Basic(_) => false,
+            Input::Full { variadic, .. } => *variadic,
+        }
+    }
+
+    pub fn input_type(&self) -> InputType {
+        match self {
+            Input::Basic(_) => InputType::default(),
+            Input::Full { input_type, .. } => input_type.clone(),
+        }
+    }
+
+    pub fn values(&self) -> &[String] {
+        match self {
+            Input::Basic(_) => &[],
+            Input::Full { values, .. } => values,
+        }
+    }
+
+    pub fn pattern(&self) -> Option<&str> {
+        match self {
+            Input::Basic(_) => None,
+            Input::Full { pattern, .. } => pattern.as_deref(),
+        }
+    }
+
+    /// A short, human readable description of the values this `Input` accepts
+    pub fn type_description(&self) -> String {
+        match self.input_type() {
+            InputType::Enum => format!("one of: {}", self.values().join(", ")),
+            InputType::Regex => format!("matching: {}", self.pattern().unwrap_or_default()),
+            t => t.to_string(),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let name = self.name();
+        let input_type = self.input_type();
+
+        if input_type == InputType::Enum {
+            if self.values().is_empty() {
+                return Err(anyhow!("Enum input {name} has no values"));
+            }
+        } else if !self.values().is_empty() {
+            return Err(anyhow!(
+                "Input {name} has values, but is of type {input_type}"
+            ));
+        }
+
+        match (input_type == InputType::Regex, self.pattern()) {
+            (true, None) | (true, Some("")) => {
+                return Err(anyhow!("Regex input {name} has no pattern"))
+            }
+            (false, Some(_)) => {
+                return Err(anyhow!(
+                    "Input {name} has a pattern, but is of type {input_type}"
+                ))
+            }
+            _ => {}
+        }
+
+        if self.variadic() {
+            if input_type != InputType::String {
+                return Err(anyhow!(
+                    "Variadic input {name} must be of type string, not {input_type}"
+                ));
+            }
+            if self.default_value().is_some() {
+                return Err(anyhow!("Variadic input {name} can not have a default"));
+            }
+        }
+
+        if let Some(d) = self.default_value() {
+            if input_type == InputType::Enum && !self.values().iter().any(|v| v == d) {
+                return Err(anyhow!(
+                    "Default {d:?} of input {name} is not one of its values"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Script snippets that are only run in one phase
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct PhaseScript {
+    /// Script snippet run before the `Command`s `script`
+    #[serde(default)]
+    pub setup: String,
+    /// Script snippet run after the `Command`s `script`
+    #[serde(default)]
+    pub script: String,
+}
+
+fn default_file_mode() -> u32 {
+    0o644
+}
+
+fn default_file_owner() -> String {
+    "root:root".to_string()
+}
+
+fn default_file_phase() -> crate::Phases {
+    crate::Phases::Polish
+}
+
+/// A file a `Command` installs into the root filesystem
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct FileDefinition {
+    /// The absolute path of the file in the root filesystem
+    path: String,
+    /// The contents of the file
+    content: Option<String>,
+    /// The file to copy, relative to the data directory of the command
+    source: Option<String>,
+    /// The file mode
+    #[serde(default = "default_file_mode")]
+    mode: u32,
+    /// The owner of the file as `user:group`
+    #[serde(default = "default_file_owner")]
+    owner: String,
+    /// The phase to install the file in
+    #[serde(default = "default_file_phase")]
+    phase: crate::Phases,
+}
+
+impl FileDefinition {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn phase(&self) -> &crate::Phases {
+        &self.phase
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let path = &self.path;
+        let is_relative_path = |p: &str| {
+            Path::new(p)
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+        };
+
+        if !path.starts_with('/') || !is_relative_path(&path[1..]) || path.len() == 1 {
+            return Err(anyhow!(
+                "File path {path:?} must be absolute and normalized"
+            ));
+        }
+        match (&self.content, &self.source) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("File {path} has both content and a source"));
+            }
+            (None, None) => {
+                return Err(anyhow!("File {path} has neither content nor a source"));
+            }
+            (None, Some(source)) if source.is_empty() || !is_relative_path(source) => {
+                return Err(anyhow!(
+                    "Source {source:?} of file {path} must be relative to the data directory"
+                ));
+            }
+            _ => {}
+        }
+        if self.mode > 0o7777 {
+            return Err(anyhow!("File {path} has invalid mode {:o}", self.mode));
+        }
+        if self.owner.is_empty() {
+            return Err(anyhow!("File {path} has no owner"));
+        }
+        if self.phase >= crate::Phases::Test {
+            return Err(anyhow!(
+                "File {path} must be installed before the {} phase",
+                crate::Phases::Test
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A directory with data files owned by a command (`foo.d` next to `foo.toml`)
+#[derive(Clone, Debug)]
+pub enum DataDirectory {
+    /// Embedded into the binary for builtin commands
+    Builtin(&'static include_dir::Dir<'static>),
+    /// A directory on the host
+    Path(PathBuf),
+}
+
+impl DataDirectory {
+    /// Make the data available on the host, extracting builtin data into
+    /// `scratch_directory` if necessary
+    pub fn host_path(&self, scratch_directory: &Path) -> anyhow::Result<PathBuf> {
+        match self {
+            DataDirectory::Builtin(dir) => {
+                let host_path = scratch_directory.join(dir.path());
+                if !host_path.is_dir() {
+                    std::fs::create_dir_all(&host_path)
+                        .and_then(|_| dir.extract(scratch_directory))
+                        .context(format!(
+                            "Failed to extract builtin data directory {:?}",
+                            dir.path()
+                        ))?;
+                }
+                Ok(host_path)
+            }
+            DataDirectory::Path(path) => Ok(path.clone()),
         }
     }
 }
@@ -142,9 +427,47 @@ pub struct Command {
     #[serde(default)]
     inputs: Vec<Input>,
 
-    /// Script snippet
+    /// Script snippet run in all phases
+    #[serde(default)]
     pub script: String,
 
+    /// Script snippets run in one phase only
+    #[serde(default)]
+    phases: BTreeMap<crate::Phases, PhaseScript>,
+
+    /// The only phases the command runs in. Empty for all phases
+    #[serde(default)]
+    applies_to_phases: Vec<crate::Phases>,
+
+    /// Files to install into the root filesystem, after the script snippets
+    /// of their phase ran
+    #[serde(default)]
+    files: Vec<FileDefinition>,
+
+    /// The interpreter running the script snippets instead of the agent
+    /// shell. The snippets get the inputs of the command as arguments and
+    /// the build context as environment variables. They can not call other
+    /// commands directly, but what they run is not known, so no command gets
+    /// pruned from agent scripts reaching this one
+    #[serde(default)]
+    interpreter: Option<String>,
+
+    /// The phases this command needs network access in
+    #[serde(default)]
+    needs_network: Vec<crate::Phases>,
+
+    /// The distributions this command supports. Empty for all
+    #[serde(default)]
+    distributions: Vec<crate::Distributions>,
+
+    /// The hook this command gets run from
+    #[serde(default)]
+    attach_to: Option<CommandName>,
+
+    /// Commands attached to the same hook run in order of ascending priority
+    #[serde(default)]
+    priority: i64,
+
     /// The source of the command itself.
     #[serde(skip)]
     source_location: String,
@@ -155,7 +478,15 @@ pub struct Command {
 
     /// The definition this command overwrites
     #[serde(skip)]
-    overwrote_definition_in: Vec<String>,
+    overwritten: Option<Box<Command>>,
+
+    /// The sub-directory the command was found in
+    #[serde(skip)]
+    group: String,
+
+    /// The data directory next to the command definition
+    #[serde(skip)]
+    data_directory: Option<DataDirectory>,
 }
 
 impl Command {
@@ -163,9 +494,180 @@ impl Command {
         self.inputs.iter()
     }
 
+    /// The script snippets for one `phase` only
+    pub fn phase_script(&self, phase: &crate::Phases) -> Option<&PhaseScript> {
+        self.phases.get(phase)
+    }
+
+    /// All script snippets of this command, independent of phase and of
+    /// what interpreter runs them
+    pub fn snippets(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.script.as_str()).chain(
+            self.phases
+                .values()
+                .flat_map(|ps| [ps.setup.as_str(), ps.script.as_str()]),
+        )
+    }
+
+    /// All shell script snippets of this command, independent of phase.
+    /// Empty for commands run by another interpreter
+    pub fn scripts(&self) -> impl Iterator<Item = &str> {
+        self.snippets().filter(|_| self.interpreter.is_none())
+    }
+
+    /// The minimum and maximum (`None` for unlimited) number of arguments
+    /// this command accepts
+    pub fn argument_range(&self) -> (usize, Option<usize>) {
+        let min = self.inputs().filter(|i| !i.optional()).count();
+        let max = if self.inputs().any(|i| i.variadic()) {
+            None
+        } else {
+            Some(self.inputs.len())
+        };
+        (min, max)
+    }
+
+    /// The phases this command has a phase specific script for
+    pub fn phases(&self) -> impl Iterator<Item = &crate::Phases> {
+        self.phases.keys()
+    }
+
+    /// Does this command run in `phase`?
+    pub fn runs_in_phase(&self, phase: &crate::Phases) -> bool {
+        self.applies_to_phases.is_empty() || self.applies_to_phases.contains(phase)
+    }
+
+    /// The files this command installs into the root filesystem
+    pub fn files(&self) -> &[FileDefinition] {
+        &self.files
+    }
+
+    /// The interpreter running the script snippets, if it is not the agent
+    /// shell
+    pub fn interpreter(&self) -> Option<&str> {
+        self.interpreter.as_deref()
+    }
+
+    /// The phases this command needs network access in
+    pub fn needs_network(&self) -> &[crate::Phases] {
+        &self.needs_network
+    }
+
+    /// The distributions this command supports. Empty if it supports all
+    pub fn distributions(&self) -> &[crate::Distributions] {
+        &self.distributions
+    }
+
+    pub fn supports_distribution(&self, distribution: &crate::Distributions) -> bool {
+        self.distributions.is_empty() || self.distributions.contains(distribution)
+    }
+
+    /// Where this command was defined
+    pub fn source_location(&self) -> &str {
+        &self.source_location
+    }
+
     pub fn dump_source(&self) -> &str {
         &self.source
     }
+
+    /// The hook this command is attached to
+    pub fn attach_to(&self) -> Option<&CommandName> {
+        self.attach_to.as_ref()
+    }
+
+    pub fn priority(&self) -> i64 {
+        self.priority
+    }
+
+    /// The sub-directory of the command path this command was defined in.
+    /// Empty for commands at the top level
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+
+    /// The data directory owned by this command
+    pub fn data_directory(&self) -> Option<&DataDirectory> {
+        self.data_directory.as_ref()
+    }
+
+    /// The definition shadowed by this command
+    pub fn overwritten(&self) -> Option<&Command> {
+        self.overwritten.as_deref()
+    }
+
+    /// The source locations of all shadowed definitions, oldest first
+    pub fn overwrote_definition_in(&self) -> Vec<&str> {
+        let mut result = vec![];
+        let mut current = self.overwritten();
+        while let Some(c) = current {
+            result.push(c.source_location.as_str());
+            current = c.overwritten();
+        }
+        result.reverse();
+        result
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for i in self.inputs() {
+            i.validate()?;
+        }
+
+        if let Some(i) = self.inputs.iter().rev().skip(1).find(|i| i.variadic()) {
+            return Err(anyhow!(
+                "Variadic input {} must be the last input",
+                i.name()
+            ));
+        }
+
+        for f in self.files() {
+            f.validate()?;
+        }
+
+        if let Some(f) = self.files().iter().find(|f| !self.runs_in_phase(f.phase())) {
+            return Err(anyhow!(
+                "Installs {} in phase {}, which is not in applies_to_phases",
+                f.path(),
+                f.phase()
+            ));
+        }
+
+        if let Some(phase) = self.phases().find(|p| !self.runs_in_phase(p)) {
+            return Err(anyhow!(
+                "Has a script for phase {phase}, which is not in applies_to_phases"
+            ));
+        }
+
+        if let Some(interpreter) = self.interpreter() {
+            if !Path::new(interpreter).is_absolute() {
+                return Err(anyhow!(
+                    "Interpreter {interpreter:?} must be an absolute path"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make sure all file sources are available in the data directory
+    fn validate_file_sources(&self) -> anyhow::Result<()> {
+        for source in self.files().iter().filter_map(|f| f.source()) {
+            let found = match &self.data_directory {
+                Some(DataDirectory::Builtin(dir)) => {
+                    dir.get_file(dir.path().join(source)).is_some()
+                }
+                Some(DataDirectory::Path(path)) => path.join(source).is_file(),
+                None => false,
+            };
+            if !found {
+                return Err(anyhow!(
+                    "File source {source:?} not found in data directory of {}",
+                    self.source_location
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Display for Command {
@@ -185,10 +687,82 @@ impl std::fmt::Display for Command {
                 } else {
                     String::new()
                 };
-                writeln!(f, "    {}{}", i.name(), help)?
+                let default = if let Some(default) = i.default_value() {
+                    format!("; default: {default:?}")
+                } else {
+                    String::new()
+                };
+                let variadic = if i.variadic() { "..." } else { "" };
+                writeln!(
+                    f,
+                    "    {}{variadic} [{}{}]{}",
+                    i.name(),
+                    i.type_description(),
+                    default,
+                    help
+                )?
             }
         }
-        let overwrote = &self.overwrote_definition_in;
+        if !self.applies_to_phases.is_empty() {
+            writeln!(
+                f,
+                "  only runs in phases: {}",
+                self.applies_to_phases
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        if !self.phases.is_empty() {
+            writeln!(
+                f,
+                "  phases: {}",
+                self.phases()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        if !self.distributions.is_empty() {
+            writeln!(
+                f,
+                "  distributions: {}",
+                self.distributions
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        if let Some(interpreter) = &self.interpreter {
+            writeln!(f, "  interpreter: {interpreter}")?;
+        }
+        if !self.needs_network.is_empty() {
+            writeln!(
+                f,
+                "  needs network in phases: {}",
+                self.needs_network
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        if !self.files.is_empty() {
+            writeln!(f, "  files:")?;
+            for file in &self.files {
+                writeln!(
+                    f,
+                    "    {} [{:04o} {}, {}]",
+                    file.path, file.mode, file.owner, file.phase
+                )?;
+            }
+        }
+        if let Some(hook) = &self.attach_to {
+            writeln!(f, "  attached to: {hook} (priority {})", self.priority)?;
+        }
+        let overwrote = self.overwrote_definition_in();
         if !overwrote.is_empty() {
             writeln!(
                 f,
@@ -196,10 +770,138 @@ impl std::fmt::Display for Command {
                 overwrote.join(", ")
             )?;
         }
-        writeln!(f)
+        Ok(())
+    }
+}
+
+/// Machine readable information about an `Input`
+#[derive(Debug, serde::Serialize)]
+pub struct InputDescription {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+    #[serde(rename = "type")]
+    pub input_type: String,
+    pub optional: bool,
+    pub variadic: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+    /// The regular expression values of regex inputs must match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+/// Machine readable information about a file a `Command` installs
+#[derive(Debug, serde::Serialize)]
+pub struct FileDescription {
+    pub path: String,
+    /// The file mode in octal
+    pub mode: String,
+    pub owner: String,
+    pub phase: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// The source file in the data directory of the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// Machine readable information about a `Command`
+#[derive(Debug, serde::Serialize)]
+pub struct CommandDescription {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+    pub group: String,
+    pub inputs: Vec<InputDescription>,
+    pub distributions: Vec<String>,
+    /// The phases the command runs in
+    pub applies_to_phases: Vec<String>,
+    /// The phases the command needs network access in
+    pub needs_network: Vec<String>,
+    pub files: Vec<FileDescription>,
+    /// The interpreter running the script, if it is not the agent shell
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interpreter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attach_to: Option<String>,
+    /// The order of commands attached to the same hook, ascending
+    pub priority: i64,
+    pub source_location: String,
+    /// The source locations of all shadowed definitions, oldest first
+    pub overwrote_definition_in: Vec<String>,
+    /// The definition as written, only included when dumping a single command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl CommandDescription {
+    fn new(name: &CommandName, command: &Command) -> Self {
+        Self {
+            name: name.to_string(),
+            help: command.help.as_ref().map(|h| h.trim().to_string()),
+            group: command.group().to_string(),
+            inputs: command
+                .inputs()
+                .map(|i| InputDescription {
+                    name: i.name().to_string(),
+                    help: i.help(),
+                    input_type: i.input_type().to_string(),
+                    optional: i.optional(),
+                    variadic: i.variadic(),
+                    default: i.default_value().map(|d| d.to_string()),
+                    values: i.values().to_vec(),
+                    pattern: i.pattern().map(|p| p.to_string()),
+                })
+                .collect(),
+            distributions: command
+                .distributions()
+                .iter()
+                .map(|d| d.to_string())
+                .collect(),
+            applies_to_phases: crate::Phases::iter()
+                .filter(|p| command.runs_in_phase(p))
+                .map(|p| p.to_string())
+                .collect(),
+            needs_network: command
+                .needs_network()
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            files: command
+                .files()
+                .iter()
+                .map(|f| FileDescription {
+                    path: f.path().to_string(),
+                    mode: format!("{:04o}", f.mode()),
+                    owner: f.owner().to_string(),
+                    phase: f.phase().to_string(),
+                    content: f.content().map(|c| c.to_string()),
+                    source: f.source().map(|s| s.to_string()),
+                })
+                .collect(),
+            interpreter: command.interpreter().map(|i| i.to_string()),
+            attach_to: command.attach_to().map(|h| h.to_string()),
+            priority: command.priority(),
+            source_location: command.source_location.clone(),
+            overwrote_definition_in: command
+                .overwrote_definition_in()
+                .into_iter()
+                .map(|l| l.to_string())
+                .collect(),
+            source: None,
+        }
     }
 }
 
+/// Machine readable list of `Command`s
+#[derive(Debug, serde::Serialize)]
+pub struct CommandList {
+    pub commands: Vec<CommandDescription>,
+}
+
 /// The toml file structure holding a `Command` as defined
 #[derive(Debug, serde::Deserialize)]
 struct TomlCommand {
@@ -220,6 +922,11 @@ impl TomlCommand {
         let mut header = toml::from_str::<TomlCommand>(contents)
             .context("Failed to parse command definition")?;
 
+        header
+            .command
+            .validate()
+            .context(format!("Invalid command definition in {source_location}"))?;
+
         header.command.source_location = source_location.to_string();
         header.command.source = contents.to_string();
 
@@ -243,7 +950,11 @@ impl Default for CommandManagerBuilder {
             let contents = f.contents_utf8().unwrap();
             let name = CommandName::try_from(f.path()).unwrap();
 
-            let cmd = TomlCommand::from_str(contents, "<builtin>").unwrap();
+            let mut cmd = TomlCommand::from_str(contents, "<builtin>").unwrap();
+            cmd.data_directory = BUILTIN_COMMANDS_DIR
+                .get_dir(f.path().with_extension("d"))
+                .map(DataDirectory::Builtin);
+            cmd.validate_file_sources().unwrap();
             result.commands.insert(name, cmd);
         }
 
@@ -253,32 +964,95 @@ impl Default for CommandManagerBuilder {
 
 impl CommandManagerBuilder {
     pub fn build(&self) -> CommandManager {
+        let mut attachments = BTreeMap::<CommandName, Vec<CommandName>>::new();
+        for (name, command) in &self.commands {
+            if let Some(hook) = command.attach_to() {
+                attachments
+                    .entry(hook.clone())
+                    .or_default()
+                    .push(name.clone());
+            }
+        }
+        for attached in attachments.values_mut() {
+            attached.sort_by_key(|n| (self.commands[n].priority(), n.clone()));
+        }
+
         CommandManager {
             commands: self.commands.clone(),
+            attachments,
         }
     }
 
+    /// Add all commands found in `command_directory` and its sub-directories
+    ///
+    /// Commands in sub-directories are prefixed with the directory names,
+    /// so `net/wireguard.toml` defines `net_wireguard`. A `-` in a directory
+    /// name turns into `_`, all other characters must be valid in a command
+    /// name. Commands found earlier get overwritten, unless they were found
+    /// in `command_directory` as well.
     pub fn scan_for_commands(&mut self, command_directory: &Path) -> anyhow::Result<()> {
-        let contents = std::fs::read_dir(command_directory).context(format!(
-            "Failed to scan for commands in {command_directory:?}"
-        ))?;
+        self.scan_directory(command_directory, &[], &mut BTreeMap::new())
+    }
+
+    fn scan_directory(
+        &mut self,
+        directory: &Path,
+        group: &[String],
+        found: &mut BTreeMap<CommandName, PathBuf>,
+    ) -> anyhow::Result<()> {
+        let mut contents = std::fs::read_dir(directory)
+            .context(format!("Failed to scan for commands in {directory:?}"))?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context(format!("Failed to scan for commands in {directory:?}"))?;
+        contents.sort();
 
         for p in contents {
-            let p = p?.path();
-            if p.is_file() && p.extension() == Some(OsStr::new("toml")) {
+            let file_name = p
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if file_name.starts_with('.') {
+                continue;
+            }
+
+            if p.is_dir() {
+                if p.extension() == Some(OsStr::new("d")) {
+                    // Data directory of a command
+                    continue;
+                }
+                let group_name = file_name.replace('-', "_");
+                validate_command_name(&group_name)
+                    .context(format!("Directory {p:?} is not a valid command group"))?;
+                let mut group = group.to_vec();
+                group.push(group_name);
+                self.scan_directory(&p, &group, found)?;
+            } else if p.is_file() && p.extension() == Some(OsStr::new("toml")) {
                 let mut cmd = TomlCommand::read_from_file(&p)
                     .context(format!("Failed to read command from {p:?}"))?;
 
-                let name = CommandName::try_from(&p as &Path)?;
+                let name = CommandName::try_from(&p as &Path)
+                    .context(format!("Failed to derive command name for {p:?}"))?;
+                let name = if group.is_empty() {
+                    name
+                } else {
+                    CommandName::try_from(format!("{}_{name}", group.join("_")))
+                        .context(format!("Failed to derive command name for {p:?}"))?
+                };
 
-                if let Some(old) = self.commands.get(&name) {
-                    cmd.overwrote_definition_in = old
-                        .overwrote_definition_in
-                        .iter()
-                        .cloned()
-                        .chain(std::iter::once(&old.source_location).cloned())
-                        .collect();
+                cmd.group = group.join("/");
+                let data_directory = p.with_extension("d");
+                if data_directory.is_dir() {
+                    cmd.data_directory = Some(DataDirectory::Path(data_directory));
+                }
+                cmd.validate_file_sources()?;
+                if let Some(other) = found.get(&name) {
+                    return Err(anyhow!(
+                        "Command {name} is defined in {other:?} and in {p:?}"
+                    ));
                 }
+                found.insert(name.clone(), p.clone());
+                cmd.overwritten = self.commands.remove(&name).map(Box::new);
 
                 self.commands.insert(name, cmd);
             }
@@ -290,6 +1064,8 @@ impl CommandManagerBuilder {
 #[derive(Clone, Debug)]
 pub struct CommandManager {
     commands: BTreeMap<CommandName, Command>,
+    /// Hook name -> attached commands in the order they need to run in
+    attachments: BTreeMap<CommandName, Vec<CommandName>>,
 }
 
 impl CommandManager {
@@ -309,17 +1085,100 @@ impl CommandManager {
         self.commands.is_empty()
     }
 
-    pub fn list_commands(&self, verbose: bool) -> String {
+    /// The commands attached to `hook`, in the order they run in
+    pub fn attached_to(&self, hook: &CommandName) -> &[CommandName] {
+        self.attachments
+            .get(hook)
+            .map(|a| a.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// The phases that need network access when running `start_command`,
+    /// together with the commands declaring that need
+    pub fn network_requirements(
+        &self,
+        start_command: &CommandName,
+    ) -> BTreeMap<crate::Phases, Vec<CommandName>> {
+        let reachable = self.call_graph().reachable_from(start_command);
+        let mut result = BTreeMap::<crate::Phases, Vec<CommandName>>::new();
+        for (name, command) in self.commands().filter(|(n, _)| reachable.contains(*n)) {
+            let mut current = Some(command);
+            while let Some(c) = current {
+                for phase in c.needs_network() {
+                    let needed_by = result.entry(phase.clone()).or_default();
+                    if !needed_by.contains(name) {
+                        needed_by.push(name.clone());
+                    }
+                }
+                current = c.overwritten();
+            }
+        }
+        result
+    }
+
+    pub fn call_graph(&self) -> crate::call_graph::CallGraph {
+        crate::call_graph::CallGraph::new(self)
+    }
+
+    /// Check calls between commands for problems. See `CallGraph::issues`.
+    pub fn check_calls(
+        &self,
+        start_command: Option<&CommandName>,
+        arguments: &[String],
+    ) -> Vec<crate::call_graph::Issue> {
+        self.call_graph().issues(self, start_command, arguments)
+    }
+
+    /// List all commands, or only those supporting `distribution`
+    pub fn list_commands(
+        &self,
+        verbose: bool,
+        distribution: Option<&crate::Distributions>,
+    ) -> String {
+        let mut result = String::new();
+
+        let mut groups = BTreeMap::<&str, Vec<(&CommandName, &Command)>>::new();
+        self.commands
+            .iter()
+            .filter(|(_, c)| distribution.map_or(true, |d| c.supports_distribution(d)))
+            .for_each(|(n, c)| groups.entry(c.group()).or_default().push((n, c)));
+
+        for (group, commands) in groups {
+            if !group.is_empty() {
+                if !result.is_empty() && !verbose {
+                    result += "\n";
+                }
+                result += &format!("{group}/\n");
+            }
+            result += &self.list_group(commands, verbose);
+        }
+
+        result
+    }
+
+    fn list_group(&self, commands: Vec<(&CommandName, &Command)>, verbose: bool) -> String {
         let mut result = String::new();
         let default_value = "no help".to_string();
 
         if verbose {
-            self.commands.iter().for_each(|(name, command)| {
+            commands.into_iter().for_each(|(name, command)| {
                 result += &format!("{name}:\n");
                 result += &format!("{command}");
+                let attached = self.attached_to(name);
+                if !attached.is_empty() {
+                    result += &format!(
+                        "  attached commands: {}\n",
+                        attached
+                            .iter()
+                            .map(|a| a.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+                result += "\n";
             });
         } else {
-            self.commands.iter().for_each(|(name, command)| {
+            commands.into_iter().for_each(|(name, command)| {
                 let help: &str = command.help.as_ref().unwrap_or(&default_value);
                 result += &format!("  {name}:\t{help}\n");
             });
@@ -328,17 +1187,589 @@ impl CommandManager {
         result
     }
 
+    /// Machine readable information about all commands, optionally only
+    /// those supporting `distribution`
+    pub fn describe_commands(&self, distribution: Option<&crate::Distributions>) -> CommandList {
+        CommandList {
+            commands: self
+                .commands
+                .iter()
+                .filter(|(_, c)| distribution.map_or(true, |d| c.supports_distribution(d)))
+                .map(|(n, c)| CommandDescription::new(n, c))
+                .collect(),
+        }
+    }
+
+    /// Machine readable information about the command `name`, including its
+    /// source
+    pub fn describe_command(&self, name: &CommandName) -> anyhow::Result<CommandDescription> {
+        let command = self.command(name)?;
+        let mut result = CommandDescription::new(name, command);
+        result.source = Some(command.dump_source().to_string());
+        Ok(result)
+    }
+
     pub fn show_command(&self, name: &CommandName) -> anyhow::Result<String> {
         let Some(command) = self.commands.get(name) else {
             return Err(anyhow::anyhow!("Unknown command: {name}"));
         };
 
-        Ok(format!("{command}"))
+        Ok(format!("{command}\n"))
     }
 }
 
 impl std::fmt::Display for CommandManager {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.list_commands(false))
+        write!(f, "{}", self.list_commands(false, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> anyhow::Result<Command> {
+        TomlCommand::from_str(contents, "<test>")
+    }
+
+    #[test]
+    fn test_builtin_commands_parse() {
+        let manager = CommandManagerBuilder::default().build();
+        assert!(!manager.is_empty());
+    }
+
+    #[test]
+    fn test_input_type_default() {
+        let cmd = parse(
+            r#"
+[command]
+inputs = [ "foo", { name = "bar" } ]
+script = ""
+"#,
+        )
+        .unwrap();
+
+        assert!(cmd.inputs().all(|i| i.input_type() == InputType::String));
+    }
+
+    #[test]
+    fn test_input_type_enum() {
+        let cmd = parse(
+            r#"
+[command]
+inputs = [ { name = "foo", type = "enum", values = [ "a", "b" ] } ]
+script = ""
+"#,
+        )
+        .unwrap();
+
+        let input = cmd.inputs().next().unwrap();
+        assert_eq!(input.input_type(), InputType::Enum);
+        assert_eq!(input.type_description(), "one of: a, b");
+    }
+
+    #[test]
+    fn test_input_type_enum_without_values() {
+        assert!(parse(
+            r#"
+[command]
+inputs = [ { name = "foo", type = "enum" } ]
+script = ""
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_input_type_regex_without_pattern() {
+        assert!(parse(
+            r#"
+[command]
+inputs = [ { name = "foo", type = "regex" } ]
+script = ""
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_input_type_pattern_on_wrong_type() {
+        assert!(parse(
+            r#"
+[command]
+inputs = [ { name = "foo", type = "bool", pattern = "^on$" } ]
+script = ""
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_input_default() {
+        let cmd = parse(
+            r#"
+[command]
+inputs = [ { name = "foo", default = "bar" } ]
+script = ""
+"#,
+        )
+        .unwrap();
+
+        let input = cmd.inputs().next().unwrap();
+        assert_eq!(input.default_value(), Some("bar"));
+        assert!(input.optional());
+    }
+
+    #[test]
+    fn test_input_default_not_in_enum() {
+        assert!(parse(
+            r#"
+[command]
+inputs = [ { name = "foo", type = "enum", values = [ "a", "b" ], default = "c" } ]
+script = ""
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_input_variadic() {
+        let cmd = parse(
+            r#"
+[command]
+inputs = [ "foo", { name = "bar", rest = true } ]
+script = ""
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            cmd.inputs().map(|i| i.variadic()).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+    }
+
+    #[test]
+    fn test_input_variadic_not_last() {
+        assert!(parse(
+            r#"
+[command]
+inputs = [ { name = "foo", variadic = true }, "bar" ]
+script = ""
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_phase_scripts() {
+        let cmd = parse(
+            r#"
+[command]
+script = "sub_command"
+
+[command.phases.prepare]
+setup = "before"
+
+[command.phases.polish]
+script = "after"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            cmd.phases().collect::<Vec<_>>(),
+            vec![&crate::Phases::Prepare, &crate::Phases::Polish]
+        );
+        assert_eq!(
+            cmd.phase_script(&crate::Phases::Prepare).unwrap().setup,
+            "before"
+        );
+        assert_eq!(
+            cmd.phase_script(&crate::Phases::Polish).unwrap().script,
+            "after"
+        );
+        assert!(cmd.phase_script(&crate::Phases::Install).is_none());
+    }
+
+    #[test]
+    fn test_phase_scripts_unknown_phase() {
+        assert!(parse(
+            r#"
+[command]
+[command.phases.foobar]
+script = "after"
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_input_type_unknown() {
+        assert!(parse(
+            r#"
+[command]
+inputs = [ { name = "foo", type = "float" } ]
+script = ""
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_overwritten_definitions_are_kept() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        for dir in [&first, &second] {
+            std::fs::write(
+                dir.path().join("hook_write_pacman_conf.toml"),
+                "[command]\nscript = \"super_hook_write_pacman_conf\"\n",
+            )
+            .unwrap();
+        }
+
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(first.path()).unwrap();
+        builder.scan_for_commands(second.path()).unwrap();
+        let manager = builder.build();
+
+        let cmd = manager
+            .command(&CommandName::parse_value("hook_write_pacman_conf").unwrap())
+            .unwrap();
+        let builtin = cmd.overwritten().unwrap().overwritten().unwrap();
+        assert!(builtin.overwritten().is_none());
+        assert!(builtin.script.is_empty());
+        assert_eq!(cmd.overwrote_definition_in().len(), 2);
+        assert_eq!(cmd.overwrote_definition_in()[0], "<builtin>");
+    }
+
+    #[test]
+    fn test_describe_command() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("hook_write_pacman_conf.toml"),
+            "[command]\nhelp = \"custom\"\ninputs = [{ name = \"foo\", optional = true }]\n",
+        )
+        .unwrap();
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let name = CommandName::parse_value("hook_write_pacman_conf").unwrap();
+        let description = manager.describe_command(&name).unwrap();
+        assert_eq!(description.help.as_deref(), Some("custom"));
+        assert_eq!(description.inputs.len(), 1);
+        assert!(description.inputs[0].optional);
+        assert_eq!(description.overwrote_definition_in, ["<builtin>"]);
+        assert!(description.source.is_some());
+
+        let json = crate::OutputFormat::Json
+            .serialize(&description)
+            .unwrap()
+            .unwrap();
+        assert!(json.contains("\"overwrote_definition_in\": [\n    \"<builtin>\"\n  ]"));
+
+        let list = manager.describe_commands(None);
+        assert!(list.commands.iter().all(|c| c.source.is_none()));
+        let toml = crate::OutputFormat::Toml.serialize(&list).unwrap().unwrap();
+        assert!(toml.contains("name = \"hook_write_pacman_conf\""));
+        assert!(crate::OutputFormat::Text
+            .serialize(&list)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_describe_command_details() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("test_details.toml"),
+            r#"
+[command]
+help = "Details"
+inputs = [{ name = "id", type = "regex", pattern = "^[a-z]+$" }]
+applies_to_phases = ["polish", "test"]
+needs_network = ["polish"]
+interpreter = "/usr/bin/python3"
+attach_to = "hook_post_pacman_key_init"
+priority = -3
+
+[[command.files]]
+path = "/etc/details"
+content = "details"
+mode = 0o600
+"#,
+        )
+        .unwrap();
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let list = manager.describe_commands(None);
+        let description = list
+            .commands
+            .iter()
+            .find(|c| c.name == "test_details")
+            .unwrap();
+        assert_eq!(description.inputs[0].pattern.as_deref(), Some("^[a-z]+$"));
+        assert_eq!(description.applies_to_phases, ["polish", "test"]);
+        assert_eq!(description.needs_network, ["polish"]);
+        assert_eq!(description.interpreter.as_deref(), Some("/usr/bin/python3"));
+        assert_eq!(description.priority, -3);
+        assert_eq!(description.files.len(), 1);
+        assert_eq!(description.files[0].mode, "0600");
+
+        let json = crate::OutputFormat::Json.serialize(&list).unwrap().unwrap();
+        assert!(json.contains("\"pattern\": \"^[a-z]+$\""));
+        assert!(json
+            .contains("\"applies_to_phases\": [\n        \"polish\",\n        \"test\"\n      ]"));
+        assert!(json.contains("\"interpreter\": \"/usr/bin/python3\""));
+        assert!(json.contains("\"priority\": -3"));
+        assert!(json.contains("\"path\": \"/etc/details\""));
+        let toml = crate::OutputFormat::Toml.serialize(&list).unwrap().unwrap();
+        assert!(toml.contains("interpreter = \"/usr/bin/python3\""));
+        assert!(toml.contains("mode = \"0600\""));
+        assert!(toml.contains("phase = \"polish\""));
+    }
+
+    #[test]
+    fn test_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, priority) in [("zz_late", -5), ("aa_early", 10), ("mm_middle", 10)] {
+            std::fs::write(
+                dir.path().join(format!("{name}.toml")),
+                format!(
+                    "[command]\nattach_to = \"hook_post_pacman_key_init\"\npriority = {priority}\n"
+                ),
+            )
+            .unwrap();
+        }
+
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let attached: Vec<_> = manager
+            .attached_to(&CommandName::parse_value("hook_post_pacman_key_init").unwrap())
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(attached, vec!["zz_late", "aa_early", "mm_middle"]);
+        assert!(manager
+            .list_commands(true, None)
+            .contains("  attached commands: zz_late, aa_early, mm_middle\n"));
+    }
+
+    #[test]
+    fn test_attach_to_invalid_name() {
+        assert!(parse("[command]\nattach_to = \"not a name\"\n").is_err());
+    }
+
+    #[test]
+    fn test_phases_list() {
+        let cmd = parse(
+            r#"
+[command]
+applies_to_phases = ["polish", "test"]
+distributions = ["arch"]
+script = "foo"
+
+[command.phases.polish]
+script = "bar"
+"#,
+        )
+        .unwrap();
+        assert!(cmd.runs_in_phase(&crate::Phases::Polish));
+        assert!(!cmd.runs_in_phase(&crate::Phases::Prepare));
+        assert_eq!(
+            cmd.phase_script(&crate::Phases::Polish).unwrap().script,
+            "bar"
+        );
+        assert!(cmd.phase_script(&crate::Phases::Test).is_none());
+        assert!(cmd.supports_distribution(&crate::Distributions::Arch));
+        assert!(!cmd.supports_distribution(&crate::Distributions::SerpentOS));
+
+        // A script for a phase the command does not run in is an error:
+        assert!(parse(
+            "[command]\napplies_to_phases = [\"polish\"]\n[command.phases.test]\nscript = \"foo\"\n"
+        )
+        .is_err());
+        // So is a file installed in such a phase:
+        assert!(parse(
+            "[command]\napplies_to_phases = [\"install\"]\n[[command.files]]\npath = \"/etc/foo\"\ncontent = \"foo\"\n"
+        )
+        .is_err());
+        // So is a typo in a phase name:
+        assert!(parse("[command]\n[command.phases.polsh]\nscript = \"foo\"\n").is_err());
+        assert!(parse("[command]\napplies_to_phases = [\"polsh\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_files() {
+        let cmd = parse(
+            r#"
+[command]
+
+[[command.files]]
+path = "/etc/foo.conf"
+content = "foo"
+
+[[command.files]]
+path = "/usr/bin/foo"
+source = "foo.sh"
+mode = 0o755
+owner = "root:wheel"
+phase = "install"
+"#,
+        )
+        .unwrap();
+        let files = cmd.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].content(), Some("foo"));
+        assert_eq!(files[0].mode(), 0o644);
+        assert_eq!(files[0].owner(), "root:root");
+        assert_eq!(files[0].phase(), &crate::Phases::Polish);
+        assert_eq!(files[1].source(), Some("foo.sh"));
+        assert_eq!(files[1].mode(), 0o755);
+        assert_eq!(files[1].phase(), &crate::Phases::Install);
+
+        // A source needs a data directory to come from:
+        assert!(cmd.validate_file_sources().is_err());
+    }
+
+    #[test]
+    fn test_files_invalid() {
+        let file = |entry: &str| parse(&format!("[command]\n[[command.files]]\n{entry}\n"));
+
+        assert!(file("path = \"/etc/foo\"\ncontent = \"\"").is_ok());
+        assert!(file("path = \"etc/foo\"\ncontent = \"\"").is_err());
+        assert!(file("path = \"/etc/../foo\"\ncontent = \"\"").is_err());
+        assert!(file("path = \"/\"\ncontent = \"\"").is_err());
+        assert!(file("path = \"/etc/foo\"").is_err());
+        assert!(file("path = \"/etc/foo\"\ncontent = \"\"\nsource = \"foo\"").is_err());
+        assert!(file("path = \"/etc/foo\"\nsource = \"../foo\"").is_err());
+        assert!(file("path = \"/etc/foo\"\ncontent = \"\"\nmode = 0o17777").is_err());
+        assert!(file("path = \"/etc/foo\"\ncontent = \"\"\nphase = \"test\"").is_err());
+    }
+
+    #[test]
+    fn test_interpreter() {
+        let command =
+            parse("[command]\ninterpreter = \"/usr/bin/python3\"\nscript = \"print()\"\n").unwrap();
+        assert_eq!(command.interpreter(), Some("/usr/bin/python3"));
+        assert_eq!(command.scripts().count(), 0);
+
+        assert!(parse("[command]\ninterpreter = \"python3\"\n").is_err());
+    }
+
+    #[test]
+    fn test_distributions_unknown() {
+        assert!(parse("[command]\ndistributions = [\"gentoo\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_network_requirements() {
+        let manager = CommandManagerBuilder::default().build();
+        let pacman = CommandName::parse_value("_pacman").unwrap();
+        let requirements =
+            manager.network_requirements(&CommandName::parse_value("_distribution").unwrap());
+        assert_eq!(
+            requirements.get(&crate::Phases::Install),
+            Some(&vec![pacman])
+        );
+        assert_eq!(requirements.len(), 1);
+        assert!(manager
+            .network_requirements(&CommandName::parse_value("set_timezone").unwrap())
+            .is_empty());
+
+        assert!(parse("[command]\nneeds_network = [\"install\"]\n").is_ok());
+        assert!(parse("[command]\nneeds_network = [\"online\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_list_commands_by_distribution() {
+        let manager = CommandManagerBuilder::default().build();
+        let serpentos = manager.list_commands(false, Some(&crate::Distributions::SerpentOS));
+        assert!(!serpentos.contains("pkg_kernel"));
+        assert!(serpentos.contains("set_timezone"));
+        assert!(manager
+            .list_commands(false, Some(&crate::Distributions::Arch))
+            .contains("pkg_kernel"));
+    }
+
+    #[test]
+    fn test_scan_for_commands_recursive() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("net/site-vpn")).unwrap();
+        std::fs::create_dir_all(dir.path().join(".hidden")).unwrap();
+        std::fs::write(
+            dir.path().join("net/wireguard.toml"),
+            "[command]\nhelp = \"wg\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("net/site-vpn/up.toml"), "[command]\n").unwrap();
+        std::fs::write(dir.path().join(".hidden/broken.toml"), "garbage").unwrap();
+        std::fs::create_dir_all(dir.path().join("net/wireguard.d")).unwrap();
+        std::fs::write(dir.path().join("net/wireguard.d/wg0.toml"), "garbage").unwrap();
+
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let wireguard = manager
+            .command(&CommandName::parse_value("net_wireguard").unwrap())
+            .unwrap();
+        assert_eq!(wireguard.group(), "net");
+        assert!(matches!(
+            wireguard.data_directory(),
+            Some(DataDirectory::Path(p)) if p == &dir.path().join("net/wireguard.d")
+        ));
+        let up = manager
+            .command(&CommandName::parse_value("net_site_vpn_up").unwrap())
+            .unwrap();
+        assert_eq!(up.group(), "net/site_vpn");
+
+        let list = manager.list_commands(false, None);
+        assert!(list.contains("\nnet/\n  net_wireguard:\twg\n"));
+        assert!(list.contains("\nnet/site_vpn/\n  net_site_vpn_up:\tno help\n"));
+    }
+
+    #[test]
+    fn test_scan_for_commands_name_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("net")).unwrap();
+        std::fs::write(dir.path().join("net/wireguard.toml"), "[command]\n").unwrap();
+        std::fs::write(dir.path().join("net_wireguard.toml"), "[command]\n").unwrap();
+
+        let mut builder = CommandManagerBuilder::default();
+        let error = format!("{:#}", builder.scan_for_commands(dir.path()).unwrap_err());
+        assert!(error.contains("net/wireguard.toml"));
+        assert!(error.contains("net_wireguard.toml"));
+
+        // Overwriting a command from another command directory is fine:
+        let other = tempfile::tempdir().unwrap();
+        std::fs::write(other.path().join("net_wireguard.toml"), "[command]\n").unwrap();
+        let mut builder = CommandManagerBuilder::default();
+        builder.scan_for_commands(other.path()).unwrap();
+        std::fs::remove_file(dir.path().join("net_wireguard.toml")).unwrap();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+        let wireguard = manager
+            .command(&CommandName::parse_value("net_wireguard").unwrap())
+            .unwrap();
+        assert!(wireguard.overwritten().is_some());
+    }
+
+    #[test]
+    fn test_scan_for_commands_invalid_group() {
+        for group in ["net.vpn", "Net", "1net", "net vpn"] {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(dir.path().join(group)).unwrap();
+            std::fs::write(dir.path().join(group).join("up.toml"), "[command]\n").unwrap();
+
+            let mut builder = CommandManagerBuilder::default();
+            let error = format!("{:#}", builder.scan_for_commands(dir.path()).unwrap_err());
+            assert!(error.contains(group), "{error}");
+        }
     }
 }
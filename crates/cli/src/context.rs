@@ -9,26 +9,307 @@ use crate::printer::Printer;
 use std::{
     collections::BTreeMap,
     ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, Context as AhContext};
 use contained_command::RunEnvironment;
 
+/// The value of a context variable
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContextValue {
+    /// A single value
+    Scalar(OsString),
+    /// A list of values without duplicates, in the order they were added
+    List(Vec<OsString>),
+}
+
+/// Make sure `item` survives being part of a list: Shell code expands lists
+/// unquoted, so items must not contain white space or pattern characters
+fn check_list_item(item: &OsStr) -> anyhow::Result<()> {
+    if item.is_empty()
+        || item
+            .as_bytes()
+            .iter()
+            .any(|b| b.is_ascii_whitespace() || b"*?[".contains(b))
+    {
+        Err(anyhow!(
+            "List item {item:?} is empty or contains white space or pattern characters"
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+impl ContextValue {
+    /// The value as seen by shell code: List items are separated by spaces
+    ///
+    /// List items are checked when added, so expanding a list unquoted in
+    /// shell code results in exactly its items again.
+    pub fn to_os_string(&self) -> OsString {
+        match self {
+            ContextValue::Scalar(value) => value.clone(),
+            ContextValue::List(items) => items.join(OsStr::new(" ")),
+        }
+    }
+}
+
+impl std::fmt::Display for ContextValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextValue::Scalar(value) => write!(f, "{value:?}"),
+            ContextValue::List(items) => write!(f, "{items:?}"),
+        }
+    }
+}
+
+/// The name of the file a `BuildContext` is persisted into
+pub const CONTEXT_FILE: &str = "context.json";
+
+/// Raw bytes in a `ContextSnapshot`: Valid UTF-8 is stored as a string,
+/// anything else hex encoded
+#[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum SnapshotBytes {
+    Text(String),
+    Hex { hex: String },
+}
+
+impl SnapshotBytes {
+    pub fn to_os_string(&self) -> anyhow::Result<OsString> {
+        match self {
+            SnapshotBytes::Text(text) => Ok(OsString::from(text)),
+            SnapshotBytes::Hex { hex } => crate::protocol::decode_hex(hex),
+        }
+    }
+}
+
+impl From<&OsStr> for SnapshotBytes {
+    fn from(value: &OsStr) -> Self {
+        match value.to_str() {
+            Some(text) => SnapshotBytes::Text(text.to_string()),
+            None => SnapshotBytes::Hex {
+                hex: value
+                    .as_bytes()
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for SnapshotBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_os_string() {
+            Ok(value) => write!(f, "{value:?}"),
+            Err(_) => write!(f, "<invalid>"),
+        }
+    }
+}
+
+/// A value in a `ContextSnapshot`
+#[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum SnapshotValue {
+    Scalar(SnapshotBytes),
+    List(Vec<SnapshotBytes>),
+}
+
+impl From<&ContextValue> for SnapshotValue {
+    fn from(value: &ContextValue) -> Self {
+        match value {
+            ContextValue::Scalar(v) => SnapshotValue::Scalar(SnapshotBytes::from(v.as_os_str())),
+            ContextValue::List(items) => SnapshotValue::List(
+                items
+                    .iter()
+                    .map(|i| SnapshotBytes::from(i.as_os_str()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// A variable in a `ContextSnapshot`
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct VariableSnapshot {
+    pub name: String,
+    pub value: SnapshotValue,
+    pub is_read_only: bool,
+    pub is_internal: bool,
+    /// Who changed the variable last from the agent script
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+}
+
+/// A variable the agent script unset in a `ContextSnapshot`
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct UnsetVariableSnapshot {
+    pub name: String,
+    pub provenance: Provenance,
+}
+
+/// How far a phase got when a `ContextSnapshot` was taken
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseState {
+    /// The phase is running
+    Running,
+    /// The user entered the phase interactively
+    Entered,
+    Succeeded,
+    Failed,
+}
+
+impl std::fmt::Display for PhaseState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhaseState::Running => write!(f, "is running"),
+            PhaseState::Entered => write!(f, "was entered interactively"),
+            PhaseState::Succeeded => write!(f, "succeeded"),
+            PhaseState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// A dependency in a `ContextSnapshot`
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct DependencySnapshot {
+    pub name: String,
+    pub command: String,
+}
+
+/// The state of a `BuildContext` after a phase, as persisted on disk
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct ContextSnapshot {
+    /// The phase that ran last
+    pub phase: crate::Phases,
+    /// How far that phase got
+    pub state: PhaseState,
+    pub variables: Vec<VariableSnapshot>,
+    #[serde(default)]
+    pub unset_variables: Vec<UnsetVariableSnapshot>,
+    /// Dependencies registered in `phase`
+    pub dependencies: Vec<DependencySnapshot>,
+    pub networked_phases: Vec<crate::Phases>,
+}
+
+impl ContextSnapshot {
+    /// Load the snapshot persisted in `artifacts_directory`
+    pub fn load(artifacts_directory: &Path) -> anyhow::Result<Self> {
+        let path = artifacts_directory.join(CONTEXT_FILE);
+        let contents =
+            std::fs::read_to_string(&path).context(format!("Failed to read {path:?}"))?;
+        serde_json::from_str(&contents).context(format!("Failed to parse {path:?}"))
+    }
+}
+
+impl std::fmt::Display for ContextSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Phase {} {}", self.phase, self.state)?;
+
+        if self.variables.is_empty() {
+            writeln!(f, "  variables: <none>")?;
+        } else {
+            writeln!(f, "  variables:")?;
+            for v in &self.variables {
+                let value = match &v.value {
+                    SnapshotValue::Scalar(value) => value.to_string(),
+                    SnapshotValue::List(items) => format!(
+                        "[{}]",
+                        items
+                            .iter()
+                            .map(|i| i.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                };
+                let flags = [(v.is_read_only, "ro"), (v.is_internal, "internal")]
+                    .iter()
+                    .filter(|(set, _)| *set)
+                    .map(|(_, flag)| *flag)
+                    .collect::<Vec<_>>();
+                let mut line = format!("    {}={value}", v.name);
+                if !flags.is_empty() {
+                    line += &format!(" ({})", flags.join(", "));
+                }
+                if let Some(provenance) = &v.provenance {
+                    line += &format!(" set by {provenance}");
+                }
+                writeln!(f, "{line}")?;
+            }
+        }
+
+        if !self.unset_variables.is_empty() {
+            writeln!(f, "  unset variables:")?;
+            for v in &self.unset_variables {
+                writeln!(f, "    {} by {}", v.name, v.provenance)?;
+            }
+        }
+
+        if !self.dependencies.is_empty() {
+            writeln!(f, "  dependencies:")?;
+            for d in &self.dependencies {
+                writeln!(f, "    {} => {}", d.name, d.command)?;
+            }
+        }
+
+        if self.networked_phases.is_empty() {
+            writeln!(f, "  networked phases: <none>")
+        } else {
+            writeln!(
+                f,
+                "  networked phases: {}",
+                self.networked_phases
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ContextEntry {
     pub name: String,
-    pub value: String,
+    pub value: ContextValue,
     pub is_read_only: bool,
     pub is_internal: bool,
 }
 
+/// Who last changed a context variable
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Provenance {
+    /// The command functions running at the time, outermost first
+    pub command_stack: Vec<String>,
+    pub phase: crate::Phases,
+}
+
+impl std::fmt::Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.command_stack.is_empty() {
+            write!(f, "the agent script in phase {}", self.phase)
+        } else {
+            write!(
+                f,
+                "{} in phase {}",
+                self.command_stack.join(" -> "),
+                self.phase
+            )
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct ContextData {
-    value: OsString,
+    value: ContextValue,
     is_read_only: bool,
     is_internal: bool,
     can_inherit: bool,
+    /// Unset for variables not set by the agent script
+    provenance: Option<Provenance>,
 }
 
 #[derive(Clone, Debug)]
@@ -49,7 +330,10 @@ impl std::fmt::Display for ContextMap {
                 }
             }
             extra += ")";
-            writeln!(f, "    {k:?}={:?} {}", &v.value, extra)?;
+            if let Some(provenance) = &v.provenance {
+                extra += &format!(" set by {provenance}");
+            }
+            writeln!(f, "    {k:?}={} {}", &v.value, extra)?;
         }
         Ok(())
     }
@@ -61,7 +345,9 @@ impl ContextMap {
     }
 
     fn get_raw(&self, name: &str) -> Option<OsString> {
-        self.0.get(&OsString::from(name)).map(|cd| cd.value.clone())
+        self.0
+            .get(&OsString::from(name))
+            .map(|cd| cd.value.to_os_string())
     }
 
     fn set(
@@ -81,6 +367,28 @@ impl ContextMap {
         )
     }
 
+    /// Make sure `name` is a valid variable name that is not read-only
+    fn check_writable(&self, name: &str) -> anyhow::Result<OsString> {
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Err(anyhow!("Invalid character in variable name \"{name}\""));
+        }
+        let name = OsString::from(name);
+        if let Some(cd) = self.0.get(&name) {
+            if cd.is_read_only {
+                return Err(match &cd.provenance {
+                    Some(provenance) => {
+                        anyhow!("{name:?} is already set and marked as read-only by {provenance}")
+                    }
+                    None => anyhow!("{name:?} is already set and marked as read-only"),
+                });
+            }
+        }
+        Ok(name)
+    }
+
     fn set_raw(
         &mut self,
         name: &str,
@@ -89,30 +397,77 @@ impl ContextMap {
         is_internal: bool,
         can_inherit: bool,
     ) -> anyhow::Result<()> {
-        if name
-            .chars()
-            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
-        {
-            let name = OsString::from(name);
-            if let Some(cd) = self.0.get(&name) {
-                if cd.is_read_only {
-                    return Err(anyhow!("{name:?} is already set and marked as read-only"));
-                }
-            }
+        let name = self.check_writable(name)?;
+        // Callers acting for the agent script record provenance afterwards
+        self.0.insert(
+            name,
+            ContextData {
+                value: ContextValue::Scalar(value.to_os_string()),
+                is_read_only,
+                is_internal,
+                can_inherit,
+                provenance: None,
+            },
+        );
+        Ok(())
+    }
 
-            self.0.insert(
-                name,
-                ContextData {
-                    value: value.to_os_string(),
-                    is_read_only,
-                    is_internal,
-                    can_inherit,
-                },
-            );
-            Ok(())
+    /// The list stored in `name`. An unset or empty variable is an empty list
+    fn list_mut(&mut self, name: &str) -> anyhow::Result<&mut Vec<OsString>> {
+        let name = self.check_writable(name)?;
+        let cd = self.0.entry(name.clone()).or_insert_with(|| ContextData {
+            value: ContextValue::List(vec![]),
+            is_read_only: false,
+            is_internal: false,
+            can_inherit: false,
+            provenance: None,
+        });
+        if matches!(&cd.value, ContextValue::Scalar(v) if v.is_empty()) {
+            cd.value = ContextValue::List(vec![]);
+        }
+        match &mut cd.value {
+            ContextValue::List(items) => Ok(items),
+            ContextValue::Scalar(_) => Err(anyhow!("{name:?} does not hold a list")),
+        }
+    }
+
+    fn append(&mut self, name: &str, value: &OsStr) -> anyhow::Result<()> {
+        check_list_item(value).context(format!("Can not add to {name}"))?;
+        let items = self.list_mut(name)?;
+        if !items.iter().any(|i| i == value) {
+            items.push(value.to_os_string());
+        }
+        Ok(())
+    }
+
+    /// Remove `value` from the list in `name`. Nothing happens if `name`
+    /// is not set
+    fn remove(&mut self, name: &str, value: &OsStr) -> anyhow::Result<()> {
+        if self.0.contains_key(&OsString::from(name)) {
+            self.list_mut(name)?.retain(|i| i != value);
         } else {
-            Err(anyhow!("Invalid character in variable name \"{name}\""))
+            self.check_writable(name)?;
         }
+        Ok(())
+    }
+
+    fn provenance(&self, name: &str) -> Option<&Provenance> {
+        self.0
+            .get(&OsString::from(name))
+            .and_then(|cd| cd.provenance.as_ref())
+    }
+
+    /// Record that `provenance` changed `name` last
+    fn set_provenance(&mut self, name: &str, provenance: &Provenance) {
+        if let Some(cd) = self.0.get_mut(&OsString::from(name)) {
+            cd.provenance = Some(provenance.clone());
+        }
+    }
+
+    fn unset(&mut self, name: &str) -> anyhow::Result<()> {
+        let name = self.check_writable(name)?;
+        self.0.remove(&name);
+        Ok(())
     }
 
     fn inherit(&self) -> Self {
@@ -205,28 +560,31 @@ impl ContextBuilder {
                 (
                     OsString::from(TIMESTAMP),
                     ContextData {
-                        value: OsString::from(self.timestamp),
+                        value: ContextValue::Scalar(OsString::from(self.timestamp)),
                         is_read_only: true,
                         is_internal: false,
                         can_inherit: true,
+                        provenance: None,
                     },
                 ),
                 (
                     OsString::from(VERSION),
                     ContextData {
-                        value: OsString::from(v),
+                        value: ContextValue::Scalar(OsString::from(v)),
                         is_read_only: true,
                         is_internal: false,
                         can_inherit: true,
+                        provenance: None,
                     },
                 ),
                 (
                     OsString::from(MY_BINARY),
                     ContextData {
-                        value: myself.into_os_string(),
+                        value: ContextValue::Scalar(myself.into_os_string()),
                         is_read_only: true,
                         is_internal: true,
                         can_inherit: true,
+                        provenance: None,
                     },
                 ),
             ])),
@@ -248,10 +606,17 @@ pub struct BuildContext {
     commands: crate::commands::CommandManager,
     bootstrap_environment: crate::RunEnvironment,
     variables: ContextMap,
+    /// The phases that run with network access
     networked_phases: Vec<crate::Phases>,
+    /// The phases the user asked to run with network access
+    requested_networked_phases: Vec<crate::Phases>,
+    /// The phases the user asked to run without network access
+    offline_phases: Vec<crate::Phases>,
     scratch_dir: tempfile::TempDir,
     debug_options: Vec<crate::DebugOptions>,
     dependencies: Vec<(VariableName, CommandName)>,
+    /// Who removed variables from the agent script that are unset now
+    unset_variables: BTreeMap<String, Provenance>,
 }
 
 const ARTIFACTS_DIR: &str = "ARTIFACTS_DIR";
@@ -262,9 +627,60 @@ const TIMESTAMP: &str = "TIMESTAMP";
 const VERSION: &str = "VERSION";
 const WORK_DIR: &str = "WORK_DIR";
 
+/// The phases to run `command` with network access in: Those requested by
+/// the user and those that reachable commands declare they need
+fn resolve_networked_phases(
+    commands: &crate::commands::CommandManager,
+    command: &CommandName,
+    requested: &[crate::Phases],
+    offline: &[crate::Phases],
+) -> anyhow::Result<Vec<crate::Phases>> {
+    if let Some(phase) = requested.iter().find(|p| offline.contains(p)) {
+        return Err(anyhow!(
+            "Phase {phase} was asked to run both with and without network access"
+        ));
+    }
+
+    let mut result = requested.to_vec();
+    for (phase, needed_by) in commands.network_requirements(command) {
+        if offline.contains(&phase) {
+            return Err(anyhow!(
+                "Network access is disabled for phase {phase}, but {} need(s) it",
+                needed_by
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        result.push(phase);
+    }
+    result.sort_unstable();
+    result.dedup();
+    Ok(result)
+}
+
+/// The variables passed into the agent script without any command exporting them
+pub(crate) const SCRIPT_VARIABLES: [&str; 5] =
+    [ARTIFACTS_DIR, ROOT_DIR, TIMESTAMP, VERSION, WORK_DIR];
+
 impl Context {
     #[cfg(test)]
     pub fn test_system(&self) -> BuildContext {
+        self.build_context_with_placeholders(
+            "/foo/artifacts",
+            "/foo/work/XXXX/root_fs",
+            "/foo/work",
+        )
+        .unwrap()
+    }
+
+    fn build_context_with_placeholders(
+        &self,
+        artifacts_directory: &str,
+        root_directory: &str,
+        work_directory: &str,
+    ) -> anyhow::Result<BuildContext> {
         let mut ctx = BuildContext {
             commands: self.commands.clone(),
             printer: self.printer.clone(),
@@ -273,25 +689,78 @@ impl Context {
                 "/tmp/bootstrap_dir",
             )),
             networked_phases: Vec::default(),
-            scratch_dir: tempfile::TempDir::new().unwrap(),
+            requested_networked_phases: Vec::default(),
+            offline_phases: Vec::default(),
+            scratch_dir: tempfile::TempDir::new().context("Failed to create scratch directory")?,
             debug_options: vec![],
             dependencies: vec![],
+            unset_variables: BTreeMap::new(),
         };
 
         ctx.variables
             .set(BUSYBOX_BINARY, "/usr/bin/busybox", true, true, true)
             .unwrap();
         ctx.variables
-            .set(ARTIFACTS_DIR, "/foo/artifacts", true, true, false)
+            .set(ARTIFACTS_DIR, artifacts_directory, true, true, false)
             .unwrap();
         ctx.variables
-            .set(ROOT_DIR, "/foo/work/XXXX/root_fs", true, true, false)
+            .set(ROOT_DIR, root_directory, true, true, false)
             .unwrap();
         ctx.variables
-            .set(WORK_DIR, "/foo/work", true, true, false)
+            .set(WORK_DIR, work_directory, true, true, false)
             .unwrap();
 
-        ctx
+        Ok(ctx)
+    }
+
+    /// Create a `BuildContext` for `command` that uses placeholder paths
+    ///
+    /// This is good enough to render agent scripts starting `command` with
+    /// `arguments`, but not to run them.
+    pub fn create_placeholder_build_context(
+        &self,
+        command: &CommandName,
+        arguments: &[String],
+    ) -> anyhow::Result<BuildContext> {
+        self.check_command_calls(command, arguments)?;
+        self.unchecked_placeholder_build_context(command)
+    }
+
+    /// Create a placeholder `BuildContext` for `command` without validating
+    /// the commands it calls first
+    pub(crate) fn unchecked_placeholder_build_context(
+        &self,
+        command: &CommandName,
+    ) -> anyhow::Result<BuildContext> {
+        self.build_context_with_placeholders(
+            &format!("/placeholder/artifacts/{command}/{}", self.version()),
+            "/placeholder/work/scratch/root_fs",
+            "/placeholder/work",
+        )
+    }
+
+    /// Statically check all commands reachable from `command`, before
+    /// any container gets started
+    fn check_command_calls(
+        &self,
+        command: &CommandName,
+        arguments: &[String],
+    ) -> anyhow::Result<()> {
+        let mut errors = vec![];
+        for issue in self.commands.check_calls(Some(command), arguments) {
+            match issue.severity {
+                crate::call_graph::Severity::Warning => self.printer.warn(&issue.to_string()),
+                crate::call_graph::Severity::Error => errors.push(issue.to_string()),
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Command definitions failed to validate:\n  {}",
+                errors.join("\n  ")
+            ))
+        }
     }
 
     // Setter:
@@ -304,8 +773,11 @@ impl Context {
         busybox_binary: &Path,
         bootstrap_environment: crate::RunEnvironment,
         networked_phases: &[crate::Phases],
+        offline_phases: &[crate::Phases],
         debug_options: &[crate::DebugOptions],
     ) -> anyhow::Result<BuildContext> {
+        self.check_command_calls(command, &[])?;
+
         let artifacts_base_directory = util::resolve_directory(artifacts_directory)
             .context("Failed to resolve work directory")?;
 
@@ -332,9 +804,14 @@ impl Context {
             return Err(anyhow!("{busybox_binary:?} is no file or not executable"));
         }
 
-        let mut networked_phases = networked_phases.to_vec();
-        networked_phases.sort_unstable();
-        networked_phases.dedup();
+        let requested_networked_phases = networked_phases.to_vec();
+        let offline_phases = offline_phases.to_vec();
+        let networked_phases = resolve_networked_phases(
+            &self.commands,
+            command,
+            &requested_networked_phases,
+            &offline_phases,
+        )?;
 
         let debug_options = {
             let mut options = debug_options.to_vec();
@@ -350,9 +827,12 @@ impl Context {
             variables: self.variables.inherit(),
             bootstrap_environment,
             networked_phases,
+            requested_networked_phases,
+            offline_phases,
             scratch_dir,
             debug_options,
             dependencies: Default::default(),
+            unset_variables: BTreeMap::new(),
         };
 
         ctx.variables
@@ -416,6 +896,13 @@ impl std::fmt::Display for BuildContext {
         } else {
             writeln!(f, "  commands = {{\n{}  }},", self.commands)?;
         }
+        if !self.unset_variables.is_empty() {
+            writeln!(f, "  unset_variables = {{")?;
+            for (name, provenance) in &self.unset_variables {
+                writeln!(f, "    {name:?} unset by {provenance}")?;
+            }
+            writeln!(f, "  }},")?;
+        }
         if self.networked_phases.is_empty() {
             writeln!(f, "  networked_phases = {{}},")?;
         } else {
@@ -450,7 +937,22 @@ impl BuildContext {
         Ok(())
     }
 
-    pub fn create_dependent_context(&self, name: &VariableName) -> anyhow::Result<Self> {
+    /// Create the context to build the dependency `name` by running `command`
+    pub fn create_dependent_context(
+        &self,
+        name: &VariableName,
+        command: &CommandName,
+    ) -> anyhow::Result<Self> {
+        let networked_phases = resolve_networked_phases(
+            &self.commands,
+            command,
+            &self.requested_networked_phases,
+            &self.offline_phases,
+        )
+        .context(format!(
+            "Failed to set up network access for dependency {name}"
+        ))?;
+
         let artifacts_directory = self.artifacts_directory().join(format!("deps/{}", name));
         std::fs::create_dir_all(&artifacts_directory).context(format!(
             "Failed to create artifact directory for dependency {name}"
@@ -470,10 +972,13 @@ impl BuildContext {
             printer: self.printer.clone(),
             variables: self.variables.inherit(),
             bootstrap_environment: self.bootstrap_environment.clone(),
-            networked_phases: self.networked_phases.clone(),
+            networked_phases,
+            requested_networked_phases: self.requested_networked_phases.clone(),
+            offline_phases: self.offline_phases.clone(),
             scratch_dir,
             debug_options: self.debug_options.clone(),
             dependencies: Default::default(),
+            unset_variables: BTreeMap::new(),
         };
 
         dep_ctx
@@ -538,6 +1043,7 @@ impl BuildContext {
         self.variables.get_raw(name)
     }
 
+    /// Set `name` from inside cleanroom, forgetting who changed it before
     pub fn set(
         &mut self,
         name: &str,
@@ -545,10 +1051,10 @@ impl BuildContext {
         is_read_only: bool,
         is_internal: bool,
     ) -> anyhow::Result<()> {
-        self.variables
-            .set(name, value, is_read_only, is_internal, false)
+        self.set_raw(name, OsStr::new(value), is_read_only, is_internal)
     }
 
+    /// Set `name` from inside cleanroom, forgetting who changed it before
     pub fn set_raw(
         &mut self,
         name: &str,
@@ -557,18 +1063,123 @@ impl BuildContext {
         is_internal: bool,
     ) -> anyhow::Result<()> {
         self.variables
-            .set_raw(name, value, is_read_only, is_internal, false)
+            .set_raw(name, value, is_read_only, is_internal, false)?;
+        self.unset_variables.remove(name);
+        Ok(())
+    }
+
+    /// Set `name` on behalf of the agent script, recording `provenance`
+    pub fn set_by(
+        &mut self,
+        name: &str,
+        value: &OsStr,
+        is_read_only: bool,
+        provenance: &Provenance,
+    ) -> anyhow::Result<()> {
+        self.variables
+            .set_raw(name, value, is_read_only, false, false)?;
+        self.set_provenance(name, provenance);
+        Ok(())
+    }
+
+    /// Add `value` to the list in `name`, unless it is in there already
+    pub fn append(
+        &mut self,
+        name: &str,
+        value: &OsStr,
+        provenance: &Provenance,
+    ) -> anyhow::Result<()> {
+        self.variables.append(name, value)?;
+        self.set_provenance(name, provenance);
+        Ok(())
+    }
+
+    /// Remove `value` from the list in `name`
+    pub fn remove(
+        &mut self,
+        name: &str,
+        value: &OsStr,
+        provenance: &Provenance,
+    ) -> anyhow::Result<()> {
+        self.variables.remove(name, value)?;
+        if self.variables.provenance(name).is_some() {
+            self.set_provenance(name, provenance);
+        }
+        Ok(())
+    }
+
+    /// Remove the variable `name`, recording `provenance`
+    pub fn unset(&mut self, name: &str, provenance: &Provenance) -> anyhow::Result<()> {
+        self.variables.unset(name)?;
+        self.unset_variables
+            .insert(name.to_string(), provenance.clone());
+        Ok(())
+    }
+
+    fn set_provenance(&mut self, name: &str, provenance: &Provenance) {
+        self.variables.set_provenance(name, provenance);
+        self.unset_variables.remove(name);
+    }
+
+    /// Who changed `name` last from the agent script, including unsetting it
+    pub fn provenance(&self, name: &str) -> Option<&Provenance> {
+        self.variables
+            .provenance(name)
+            .or_else(|| self.unset_variables.get(name))
     }
 
     pub fn iter(&self) -> impl Iterator<Item = ContextEntry> + '_ {
         self.variables.iter().map(|(k, cd)| ContextEntry {
             name: k.to_string_lossy().to_string(),
-            value: cd.value.to_string_lossy().to_string(),
+            value: cd.value.clone(),
             is_read_only: cd.is_read_only,
             is_internal: cd.is_internal,
         })
     }
 
+    /// The state of this context in `phase`
+    pub fn snapshot(&self, phase: &crate::Phases, state: PhaseState) -> ContextSnapshot {
+        ContextSnapshot {
+            phase: phase.clone(),
+            state,
+            variables: self
+                .iter()
+                .map(|ce| VariableSnapshot {
+                    provenance: self.variables.provenance(&ce.name).cloned(),
+                    name: ce.name,
+                    value: SnapshotValue::from(&ce.value),
+                    is_read_only: ce.is_read_only,
+                    is_internal: ce.is_internal,
+                })
+                .collect(),
+            unset_variables: self
+                .unset_variables
+                .iter()
+                .map(|(name, provenance)| UnsetVariableSnapshot {
+                    name: name.clone(),
+                    provenance: provenance.clone(),
+                })
+                .collect(),
+            dependencies: self
+                .dependencies
+                .iter()
+                .map(|(name, command)| DependencySnapshot {
+                    name: name.to_string(),
+                    command: command.to_string(),
+                })
+                .collect(),
+            networked_phases: self.networked_phases.clone(),
+        }
+    }
+
+    /// Write the state in `phase` into the artifacts directory
+    pub fn persist(&self, phase: &crate::Phases, state: PhaseState) -> anyhow::Result<()> {
+        let path = self.artifacts_directory().join(CONTEXT_FILE);
+        let contents = serde_json::to_string_pretty(&self.snapshot(phase, state))
+            .context("Failed to serialize the build context")?;
+        std::fs::write(&path, contents + "\n").context(format!("Failed to write {path:?}"))
+    }
+
     pub fn command_manager_mut(&mut self) -> &mut crate::commands::CommandManager {
         &mut self.commands
     }
@@ -585,3 +1196,163 @@ impl BuildContext {
         self.debug_options.contains(debug_option)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let ctx = ContextBuilder::new_test().build().unwrap();
+        let mut ctx = ctx.test_system();
+        ctx.set("FOO", "bar", true, false).unwrap();
+        let provenance = Provenance {
+            command_stack: vec!["list_command".to_string()],
+            phase: crate::Phases::Prepare,
+        };
+        ctx.append("LIST", OsStr::new("a"), &provenance).unwrap();
+        ctx.append("LIST", OsStr::new("b"), &provenance).unwrap();
+        ctx.set_by(
+            "RAW",
+            OsStr::from_bytes(b"a\xffb"),
+            false,
+            &Provenance {
+                command_stack: vec!["raw_command".to_string()],
+                phase: crate::Phases::Install,
+            },
+        )
+        .unwrap();
+        ctx.unset(
+            "GONE",
+            &Provenance {
+                command_stack: vec!["unset_command".to_string()],
+                phase: crate::Phases::Install,
+            },
+        )
+        .unwrap();
+        ctx.add_dependency(
+            VariableName::try_from("DEP".to_string()).unwrap(),
+            CommandName::try_from("dep_command".to_string()).unwrap(),
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(CONTEXT_FILE),
+            serde_json::to_string(&ctx.snapshot(&crate::Phases::Install, PhaseState::Failed))
+                .unwrap(),
+        )
+        .unwrap();
+        let snapshot = ContextSnapshot::load(dir.path()).unwrap();
+
+        assert_eq!(snapshot.phase, crate::Phases::Install);
+        assert_eq!(snapshot.state, PhaseState::Failed);
+        let list = snapshot
+            .variables
+            .iter()
+            .find(|v| v.name == "LIST")
+            .unwrap();
+        assert_eq!(
+            list.value,
+            SnapshotValue::List(vec![
+                SnapshotBytes::Text("a".to_string()),
+                SnapshotBytes::Text("b".to_string())
+            ])
+        );
+        assert_eq!(list.provenance.as_ref(), Some(&provenance));
+        let raw = snapshot.variables.iter().find(|v| v.name == "RAW").unwrap();
+        let SnapshotValue::Scalar(raw_value) = &raw.value else {
+            panic!("RAW is not a scalar");
+        };
+        assert_eq!(
+            raw_value,
+            &SnapshotBytes::Hex {
+                hex: "61ff62".to_string()
+            }
+        );
+        assert_eq!(
+            raw_value.to_os_string().unwrap(),
+            OsStr::from_bytes(b"a\xffb")
+        );
+        assert_eq!(snapshot.unset_variables.len(), 1);
+        assert_eq!(snapshot.unset_variables[0].name, "GONE");
+
+        let text = snapshot.to_string();
+        assert!(text.starts_with("Phase install failed\n"));
+        assert!(text.contains("\n    FOO=\"bar\" (ro)\n"));
+        assert!(text.contains("\n    LIST=[\"a\", \"b\"] set by "));
+        assert!(text.contains("\n  unset variables:\n    GONE by "));
+        assert!(text.contains("\n  dependencies:\n    DEP => dep_command\n"));
+        assert!(text.ends_with("  networked phases: <none>\n"));
+
+        assert!(ContextSnapshot::load(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_persist_phase_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = ContextBuilder::new_test().build().unwrap();
+        let ctx = ctx
+            .build_context_with_placeholders(
+                dir.path().to_str().unwrap(),
+                "/foo/work/XXXX/root_fs",
+                "/foo/work",
+            )
+            .unwrap();
+
+        ctx.persist(&crate::Phases::Install, PhaseState::Succeeded)
+            .unwrap();
+        ctx.persist(&crate::Phases::Polish, PhaseState::Entered)
+            .unwrap();
+        let snapshot = ContextSnapshot::load(dir.path()).unwrap();
+        assert_eq!(snapshot.phase, crate::Phases::Polish);
+        assert_eq!(snapshot.state, PhaseState::Entered);
+        assert!(snapshot
+            .to_string()
+            .starts_with("Phase polish was entered interactively\n"));
+    }
+
+    #[test]
+    fn test_list_rendering() {
+        let ctx = ContextBuilder::new_test().build().unwrap();
+        let mut ctx = ctx.test_system();
+        let provenance = Provenance {
+            command_stack: vec!["list_command".to_string()],
+            phase: crate::Phases::Prepare,
+        };
+        let items = ["a", "b=c,d", "'e'", "$f"];
+        for i in items {
+            ctx.append("LIST", OsStr::new(i), &provenance).unwrap();
+        }
+        for i in ["a b", "a\tb", "a*", "a?", "[a]", ""] {
+            assert!(ctx.append("LIST", OsStr::new(i), &provenance).is_err());
+        }
+
+        // Expanding the list unquoted in shell code gives back all items
+        let value = ctx.get("LIST").unwrap();
+        let output = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg("printf '<%s>' ${LIST}")
+            .env("LIST", &value)
+            .current_dir(tempfile::tempdir().unwrap().path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            items.map(|i| format!("<{i}>")).concat()
+        );
+    }
+
+    #[test]
+    fn test_remove_from_unset_list() {
+        let ctx = ContextBuilder::new_test().build().unwrap();
+        let mut ctx = ctx.test_system();
+        let provenance = Provenance {
+            command_stack: vec!["list_command".to_string()],
+            phase: crate::Phases::Prepare,
+        };
+        ctx.remove("LIST", OsStr::new("a"), &provenance).unwrap();
+        assert_eq!(ctx.get("LIST"), None);
+        assert!(ctx.iter().all(|ce| ce.name != "LIST"));
+    }
+}
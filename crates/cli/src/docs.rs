@@ -0,0 +1,306 @@
+// Copyright © Tobias Hunger <tobias.hunger@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Reference documentation for `Command`s
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::commands::{CommandDescription, CommandManager, CommandName};
+use crate::Phases;
+
+/// A variable exported by a `Command`
+struct Export {
+    name: String,
+    kind: &'static str,
+    phases: BTreeSet<Phases>,
+}
+
+impl Export {
+    fn phases(&self) -> String {
+        if self.phases.len() == Phases::iter().count() {
+            "all".to_string()
+        } else {
+            self.phases
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    }
+}
+
+/// Everything documented about one `Command`
+struct Page {
+    description: CommandDescription,
+    calls: Vec<CommandName>,
+    exports: Vec<Export>,
+}
+
+impl Page {
+    fn new(manager: &CommandManager, name: &CommandName) -> anyhow::Result<Self> {
+        let command = manager.command(name)?;
+        let description = manager.describe_command(name)?;
+        let calls = manager
+            .call_graph()
+            .callees(name)
+            .into_iter()
+            .filter(|c| manager.command(c).is_ok())
+            .collect();
+
+        let mut exports = BTreeMap::<(String, &'static str), Export>::new();
+        for (phases, script) in crate::check::phase_scripts(command) {
+            let parsed = crate::call_graph::parse(script);
+            for (kind, variable) in crate::check::exported_variables(&parsed) {
+                exports
+                    .entry((variable.clone(), kind))
+                    .or_insert_with(|| Export {
+                        name: variable,
+                        kind,
+                        phases: BTreeSet::new(),
+                    })
+                    .phases
+                    .extend(phases.iter().cloned());
+            }
+        }
+
+        Ok(Self {
+            description,
+            calls,
+            exports: exports.into_values().collect(),
+        })
+    }
+
+    fn help_lines(&self) -> Vec<&str> {
+        self.description
+            .help
+            .as_deref()
+            .unwrap_or("No help available.")
+            .lines()
+            .map(|l| l.trim())
+            .collect()
+    }
+
+    fn summary(&self) -> &str {
+        self.help_lines().first().copied().unwrap_or_default()
+    }
+
+    fn input_details(input: &crate::commands::InputDescription) -> String {
+        let mut details = vec![input.input_type.clone()];
+        if !input.values.is_empty() {
+            details.push(format!("one of {}", input.values.join(", ")));
+        }
+        if input.variadic {
+            details.push("takes all remaining arguments".to_string());
+        }
+        if let Some(default) = &input.default {
+            details.push(format!("default: {default:?}"));
+        } else if input.optional {
+            details.push("optional".to_string());
+        }
+        details.join("; ")
+    }
+
+    fn source(&self) -> String {
+        let d = &self.description;
+        if d.overwrote_definition_in.is_empty() {
+            d.source_location.clone()
+        } else {
+            format!(
+                "{} (overwrites {})",
+                d.source_location,
+                d.overwrote_definition_in.join(", ")
+            )
+        }
+    }
+
+    fn markdown(&self) -> String {
+        let d = &self.description;
+        let mut result = format!("# `{}`\n\n", d.name);
+        result += &self.help_lines().join("\n");
+        result += "\n\n## Inputs\n\n";
+        if d.inputs.is_empty() {
+            result += "None.\n";
+        } else {
+            result += "| Name | Details | Description |\n|---|---|---|\n";
+            for i in &d.inputs {
+                result += &format!(
+                    "| `{}` | {} | {} |\n",
+                    i.name,
+                    Self::input_details(i),
+                    i.help.as_deref().unwrap_or_default().replace('|', "\\|")
+                );
+            }
+        }
+        result += &format!("\n## Source\n\n`{}`\n", self.source());
+
+        result += "\n## Calls\n\n";
+        if self.calls.is_empty() {
+            result += "None.\n";
+        } else {
+            for c in &self.calls {
+                result += &format!("- [`{c}`]({c}.md)\n");
+            }
+        }
+
+        result += "\n## Exports\n\n";
+        if self.exports.is_empty() {
+            result += "None.\n";
+        } else {
+            result += "| Variable | Kind | Phases |\n|---|---|---|\n";
+            for e in &self.exports {
+                result += &format!("| `{}` | {} | {} |\n", e.name, e.kind, e.phases());
+            }
+        }
+        result
+    }
+
+    fn man_page(&self) -> String {
+        let d = &self.description;
+        let mut result = format!(
+            ".TH \"CLEANROOM-{}\" 7 \"\" \"cleanroom\" \"cleanroom commands\"\n",
+            roff_escape(&d.name.to_uppercase())
+        );
+        result += &format!(
+            ".SH NAME\n{} \\- {}\n",
+            roff_escape(&d.name),
+            roff_escape(self.summary())
+        );
+        result += ".SH DESCRIPTION\n";
+        for line in self.help_lines() {
+            result += &if line.is_empty() {
+                ".PP\n".to_string()
+            } else {
+                format!("{}\n", roff_escape(line))
+            };
+        }
+
+        result += ".SH INPUTS\n";
+        if d.inputs.is_empty() {
+            result += "None.\n";
+        }
+        for i in &d.inputs {
+            result += &format!(
+                ".TP\n.B {}\n{}\n",
+                roff_escape(&i.name),
+                roff_escape(&Self::input_details(i))
+            );
+            if let Some(help) = &i.help {
+                result += &format!(".br\n{}\n", roff_escape(help));
+            }
+        }
+
+        result += &format!(".SH SOURCE\n{}\n", roff_escape(&self.source()));
+
+        result += ".SH EXPORTS\n";
+        if self.exports.is_empty() {
+            result += "None.\n";
+        }
+        for e in &self.exports {
+            result += &format!(
+                ".TP\n.B {}\n{} in phases: {}\n",
+                roff_escape(&e.name),
+                e.kind,
+                e.phases()
+            );
+        }
+
+        if !self.calls.is_empty() {
+            result += &format!(
+                ".SH SEE ALSO\n{}\n",
+                self.calls
+                    .iter()
+                    .map(|c| format!("\\fBcleanroom-{}\\fR(7)", roff_escape(&c.to_string())))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        result
+    }
+}
+
+/// Escape `text` for use in a roff document
+fn roff_escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{escaped}")
+    } else {
+        escaped
+    }
+}
+
+fn write_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    std::fs::write(path, contents).context(format!("Failed to write {path:?}"))
+}
+
+/// Write Markdown documentation for all commands known to `manager` into
+/// `output`, and man pages into `output/man7` if `man_pages` is set
+pub fn generate(manager: &CommandManager, output: &Path, man_pages: bool) -> anyhow::Result<()> {
+    let man_directory = output.join("man7");
+    std::fs::create_dir_all(output).context(format!("Failed to create {output:?}"))?;
+    if man_pages {
+        std::fs::create_dir_all(&man_directory)
+            .context(format!("Failed to create {man_directory:?}"))?;
+    }
+
+    let mut index = String::from("# Commands\n\n");
+    for (name, _) in manager.commands() {
+        let page = Page::new(manager, name)?;
+        index += &format!("- [`{name}`]({name}.md): {}\n", page.summary());
+
+        write_file(&output.join(format!("{name}.md")), &page.markdown())?;
+        if man_pages {
+            write_file(
+                &man_directory.join(format!("cleanroom-{name}.7")),
+                &page.man_page(),
+            )?;
+        }
+    }
+    write_file(&output.join("index.md"), &index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown() {
+        let manager = crate::commands::CommandManagerBuilder::default().build();
+        let page = Page::new(
+            &manager,
+            &CommandName::parse_value("_distribution").unwrap(),
+        )
+        .unwrap();
+        let markdown = page.markdown();
+
+        assert!(markdown.starts_with("# `_distribution`\n\nSet the base distribution"));
+        assert!(markdown.contains("| `distribution_id` | enum; one of arch, serpentos |"));
+        assert!(markdown.contains("\n## Source\n\n`<builtin>`\n"));
+        assert!(markdown.contains("- [`_distribution_arch`](_distribution_arch.md)\n"));
+        assert!(markdown.contains("| `OS_RELEASE_NAME` | export_constant | prepare |\n"));
+        assert!(markdown.contains("| `CLRM_BASE_DISTRIBUTION` | export_constant | prepare |\n"));
+    }
+
+    #[test]
+    fn test_roff_escape() {
+        assert_eq!(roff_escape("a-b\\c"), "a\\-b\\ec");
+        assert_eq!(roff_escape(".foo"), "\\&.foo");
+        assert_eq!(roff_escape("'foo"), "\\&'foo");
+    }
+
+    #[test]
+    fn test_generate() {
+        let manager = crate::commands::CommandManagerBuilder::default().build();
+        let output = tempfile::tempdir().unwrap();
+        generate(&manager, output.path(), true).unwrap();
+
+        let index = std::fs::read_to_string(output.path().join("index.md")).unwrap();
+        assert!(index.contains("- [`_distribution`](_distribution.md): Set the base"));
+        assert!(output.path().join("_distribution.md").is_file());
+        let man_page =
+            std::fs::read_to_string(output.path().join("man7/cleanroom-_distribution.7")).unwrap();
+        assert!(man_page.starts_with(".TH \"CLEANROOM-_DISTRIBUTION\" 7"));
+    }
+}
@@ -1,8 +1,5 @@
 #!/usr/bin/sh -e
 # The entire script is run in `busybox sh -e`
-__command_prefix="${1}"
-readonly __command_prefix
-shift
 CURRENT_PHASE="${1}"
 readonly CURRENT_PHASE
 shift
@@ -12,53 +9,82 @@ readonly CLRM_DIR
 BUSYBOX="${CLRM_DIR}/busybox"
 readonly BUSYBOX
 
-status() {
-	message="${1}"
-	shift
-
-	echo "${__command_prefix}: STATUS \"${message}\""
-}
+# The agent protocol channel
+exec 3>"${CLRM_DIR}/channel"
 
 status "Setup: ${CURRENT_PHASE} (${CLRM_CONTAINER})"
 
-push_status() {
-	message="${1}"
-	shift
-
-	echo "${__command_prefix}: PUSH \"${message}\""
+__read_input() {
+	eval "__input_value=\"\${${1}}\""
 }
 
-pop_status() {
-	echo "${__command_prefix}: POP"
+check_input_bool() {
+	__input_command="${1}"
+	__input_name="${2}"
+	__read_input "${__input_name}"
+
+	case "${__input_value}" in
+	[oO][nN] | [tT][rR][uU][eE] | [yY][eE][sS] | 1)
+		eval "${__input_name}=on"
+		;;
+	[oO][fF][fF] | [fF][aA][lL][sS][eE] | [nN][oO] | 0)
+		eval "${__input_name}=off"
+		;;
+	*)
+		error "${__input_command}: Input \"${__input_name}\" expects a boolean (on/off), got \"${__input_value}\""
+		;;
+	esac
 }
 
-export_constant() {
-	key="${1}"
-	shift
+check_input_integer() {
+	__input_command="${1}"
+	__input_name="${2}"
+	__read_input "${__input_name}"
 
-	eval "${key}=\"${*}\""
-	readonly "${key}"
-	echo "${__command_prefix}: SET_RO \"${key}\"=\"${*}\""
+	case "${__input_value#-}" in
+	"" | *[!0-9]*)
+		error "${__input_command}: Input \"${__input_name}\" expects an integer, got \"${__input_value}\""
+		;;
+	esac
 }
 
-export_var() {
-	key="${1}"
-	shift
-
-	echo "${__command_prefix}: SET \"${key}\"=\"${*}\""
+check_input_path() {
+	__input_command="${1}"
+	__input_name="${2}"
+	__read_input "${__input_name}"
+
+	case "${__input_value}" in
+	/*) ;;
+	*)
+		error "${__input_command}: Input \"${__input_name}\" expects an absolute path, got \"${__input_value}\""
+		;;
+	esac
 }
 
-add_dependency() {
-	key="${1}"
+check_input_enum() {
+	__input_command="${1}"
 	shift
-
-	eval "${key}=\"${*}\""
-	echo "${__command_prefix}: ADD_DEPENDENCY \"${key}\"=\"${*}\""
+	__input_name="${1}"
+	shift
+	__read_input "${__input_name}"
+
+	for __input_allowed in "${@}"; do
+		if [ "${__input_value}" = "${__input_allowed}" ]; then
+			return 0
+		fi
+	done
+	error "${__input_command}: Input \"${__input_name}\" expects one of \"${*}\", got \"${__input_value}\""
 }
 
-error() {
-	echo "Error in Agent script: ${*}"
-	exit 1
+check_input_regex() {
+	__input_command="${1}"
+	__input_name="${2}"
+	__input_pattern="${3}"
+	__read_input "${__input_name}"
+
+	if ! printf "%s" "${__input_value}" | "${BUSYBOX}" grep -Eq -e "${__input_pattern}"; then
+		error "${__input_command}: Input \"${__input_name}\" expects a value matching \"${__input_pattern}\", got \"${__input_value}\""
+	fi
 }
 
 assert_distribution_initialized() {
@@ -67,10 +93,25 @@ assert_distribution_initialized() {
 	fi
 }
 
+check_distribution() {
+	__distribution_command="${1}"
+	shift
+
+	assert_distribution_initialized
+	for __distribution in "${@}"; do
+		[ "${CLRM_BASE_DISTRIBUTION}" = "${__distribution}" ] && return 0
+	done
+	error "${__distribution_command}: Base distribution \"${CLRM_BASE_DISTRIBUTION}\" is not supported"
+}
+
 bb_chmod() {
 	"${BUSYBOX}" chmod "${@}"
 }
 
+bb_cp() {
+	"${BUSYBOX}" cp "${@}"
+}
+
 bb_mkdir() {
 	"${BUSYBOX}" mkdir "${@}"
 }
@@ -79,4 +120,23 @@ bb_mknod() {
 	"${BUSYBOX}" mknod "${@}"
 }
 
+install_file() {
+	__file_target="${ROOT_FS%/}${1}"
+	bb_mkdir -p "$("${BUSYBOX}" dirname "${__file_target}")"
+	if [ "${4}" = "--content" ]; then
+		printf "%s" "${5}" > "${__file_target}" || error "Failed to write file ${1}"
+	else
+		bb_cp "${4}" "${__file_target}" || error "Failed to copy ${4} to ${1}"
+	fi
+	"${BUSYBOX}" chown "${3}" "${__file_target}" || error "Failed to change owner of ${1} to ${3}"
+	bb_chmod "${2}" "${__file_target}" || error "Failed to change mode of ${1} to ${2}"
+}
+
+check_file() {
+	__file_target="${ROOT_FS%/}${1}"
+	[ -f "${__file_target}" ] || error "File ${1} is missing"
+	__file_mode="$("${BUSYBOX}" stat -c "%a" "${__file_target}")"
+	[ "${__file_mode}" = "${2}" ] || error "File ${1} has mode ${__file_mode}, expected ${2}"
+}
+
 cd "${ROOT_FS}" || error "Failed to cd into ${ROOT_FS}"
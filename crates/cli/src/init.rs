@@ -36,7 +36,7 @@ fn write_out_example_files(
                     write_out_example_files(d, &new_dest_dir, distribution)?;
                 }
                 include_dir::DirEntry::File(f) => {
-                    let mut out = std::fs::File::create(&dest_dir.join(included))
+                    let mut out = std::fs::File::create(dest_dir.join(included))
                         .context("Failed to open {included}")?;
                     out.write_all(f.contents())
                         .context("Failed to write {included}")?;
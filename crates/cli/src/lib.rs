@@ -14,8 +14,37 @@ pub enum DebugOptions {
     PrintBuildContext,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, ValueEnum)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, ValueEnum)]
 #[clap(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// JSON
+    Json,
+    /// TOML
+    Toml,
+}
+
+impl OutputFormat {
+    /// Serialize `value` in this format. Returns `None` for `Text`, which
+    /// has no generic representation
+    pub fn serialize<T: serde::Serialize>(&self, value: &T) -> anyhow::Result<Option<String>> {
+        match self {
+            OutputFormat::Text => Ok(None),
+            OutputFormat::Json => serde_json::to_string_pretty(value)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Failed to generate JSON: {e}")),
+            OutputFormat::Toml => toml::to_string_pretty(value)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Failed to generate TOML: {e}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd, ValueEnum, serde::Deserialize)]
+#[clap(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum Distributions {
     /// Arch Linux
     Arch,
@@ -25,8 +54,23 @@ pub enum Distributions {
     Unknown,
 }
 
-#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd, ValueEnum)]
+impl std::fmt::Display for Distributions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.to_possible_value()
+                .map(|pv| pv.get_name().to_string())
+                .unwrap_or_else(|| "<unknown>".to_string())
+        )
+    }
+}
+
+#[derive(
+    Debug, Clone, Eq, Ord, PartialEq, PartialOrd, ValueEnum, serde::Deserialize, serde::Serialize,
+)]
 #[clap(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum Phases {
     /// Prepare for install: Set up package manager and other basic things
     Prepare,
@@ -64,10 +108,14 @@ impl std::fmt::Display for Phases {
 
 pub mod agent;
 pub mod agent_runner;
+pub mod call_graph;
+pub mod check;
 pub mod commands;
 pub mod context;
+pub mod docs;
 pub mod init;
 pub mod printer;
+mod protocol;
 pub mod scripts;
 
 // Re-Exports:
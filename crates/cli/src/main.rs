@@ -32,18 +32,31 @@ struct Arguments {
 
 #[derive(Args, Debug)]
 struct BuildAgentCommand {
-    /// The prefix used to send commands to the agent runner
-    #[arg(long, short)]
-    command_prefix: String,
     /// The phase to run
     phase: cli::Phases,
 }
 
+#[derive(Args, Debug)]
+struct CheckCommand {
+    /// The busybox binary to use for syntax checks
+    #[arg(long, default_value = "/usr/bin/busybox", env = "CLRM_BUSYBOX")]
+    busybox_binary: PathBuf,
+    /// Fail on warnings, too
+    #[arg(long)]
+    deny_warnings: bool,
+}
+
 #[derive(Args, Debug)]
 struct CommandListCommand {
     /// Print more information
     #[arg(long)]
     verbose: bool,
+    /// Only list commands supporting this distribution
+    #[arg(long, short)]
+    distribution: Option<cli::Distributions>,
+    /// The output format
+    #[arg(long, value_enum, default_value_t)]
+    format: cli::OutputFormat,
 }
 
 #[derive(Args, Debug)]
@@ -51,6 +64,52 @@ struct DumpCommand {
     /// The command to dump
     #[arg(value_parser = CommandName::parse_value)]
     name: CommandName,
+    /// The output format
+    #[arg(long, value_enum, default_value_t)]
+    format: cli::OutputFormat,
+}
+
+#[derive(Args, Debug)]
+struct DocsCommand {
+    /// The directory to write the documentation into
+    #[arg(long, short)]
+    output: PathBuf,
+    /// Generate man pages as well
+    #[arg(long)]
+    man_pages: bool,
+}
+
+#[derive(Args, Debug)]
+struct RenderScriptCommand {
+    /// The phase to render the agent script for
+    #[arg(long, short)]
+    phase: cli::Phases,
+
+    /// The timestamp to use in the script
+    #[arg(long, default_value = "19700101.0000")]
+    timestamp: String,
+
+    /// The version string to use (defaults to timestamp if unset)
+    #[arg(long)]
+    artifact_version: Option<String>,
+
+    /// The command to render the script for
+    #[arg(value_parser = CommandName::parse_value)]
+    command: CommandName,
+
+    /// The arguments to pass to the command
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    arguments: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct ShowContextCommand {
+    /// The output format
+    #[arg(long, value_enum, default_value_t)]
+    format: cli::OutputFormat,
+
+    /// The artifacts directory of a build
+    artifacts_directory: PathBuf,
 }
 
 #[derive(Args, Debug)]
@@ -104,10 +163,16 @@ struct BuildCommand {
     #[arg(long, env = "CLRM_EXTRA_BINDINGS", value_delimiter = ',')]
     extra_bindings: Vec<String>,
 
-    /// Enter a debug environment in the provided phase
+    /// Phases to run with network access, in addition to those commands
+    /// declare they need
     #[arg(long, env = "CLRM_NETWORKED_PHASES", value_delimiter = ',')]
     networked_phases: Vec<cli::Phases>,
 
+    /// Phases to run without network access. It is an error if a command
+    /// needs network access in one of these
+    #[arg(long, env = "CLRM_OFFLINE_PHASES", value_delimiter = ',')]
+    offline_phases: Vec<cli::Phases>,
+
     /// Enter a debug environment in the provided phase
     #[arg(long)]
     enter_phase: Option<cli::Phases>,
@@ -127,10 +192,18 @@ enum Commands {
     /// Run as an agent inside a container. For internal use
     #[command(hide = true)]
     BuildAgent(BuildAgentCommand),
+    /// Check all known commands for problems
+    Check(CheckCommand),
     /// Print a list of known commands
     CommandList(CommandListCommand),
     /// Dump a command definition to stdout
     DumpCommand(DumpCommand),
+    /// Write reference documentation for all known commands
+    Docs(DocsCommand),
+    /// Print the agent script for a command and phase
+    RenderScript(RenderScriptCommand),
+    /// Print the build context persisted by a build
+    ShowContext(ShowContextCommand),
     /// Initialize a directory to hold a cleanroom configuration
     Initialize(InitializeCommand),
     /// Run some command
@@ -184,6 +257,7 @@ fn create_build_context(
             &build.busybox_binary,
             bootstrap_environment,
             &build.networked_phases,
+            &build.offline_phases,
             debug_options,
         )
         .context("Failed to set up system context")?;
@@ -208,16 +282,92 @@ async fn main() -> anyhow::Result<()> {
     let args = Arguments::parse();
 
     match &args.command {
-        Commands::BuildAgent(agent) => cli::agent::run(&agent.command_prefix, &agent.phase),
+        Commands::BuildAgent(agent) => cli::agent::run(&agent.phase),
+        Commands::Check(check) => {
+            let command_manager = create_command_manager(&args.extra_command_path)?;
+            let issues = cli::check::check(&command_manager, &check.busybox_binary)?;
+            for i in &issues {
+                println!("{i}");
+            }
+            let errors = issues
+                .iter()
+                .filter(|i| i.severity == cli::call_graph::Severity::Error)
+                .count();
+            let warnings = issues.len() - errors;
+            if errors > 0 {
+                Err(anyhow::anyhow!(
+                    "Found {errors} error(s) in command definitions"
+                ))
+            } else if check.deny_warnings && warnings > 0 {
+                Err(anyhow::anyhow!(
+                    "Found {warnings} warning(s) in command definitions"
+                ))
+            } else {
+                Ok(())
+            }
+        }
         Commands::CommandList(list) => {
             let command_manager = create_command_manager(&args.extra_command_path)?;
-            println!("{}", command_manager.list_commands(list.verbose));
+            let distribution = list.distribution.as_ref();
+            match list
+                .format
+                .serialize(&command_manager.describe_commands(distribution))?
+            {
+                Some(output) => println!("{output}"),
+                None => println!(
+                    "{}",
+                    command_manager.list_commands(list.verbose, distribution)
+                ),
+            }
             Ok(())
         }
         Commands::DumpCommand(dc) => {
             let command_manager = create_command_manager(&args.extra_command_path)?;
-            let cmd = command_manager.command(&dc.name)?;
-            println!("{}", cmd.dump_source());
+            match dc
+                .format
+                .serialize(&command_manager.describe_command(&dc.name)?)?
+            {
+                Some(output) => println!("{output}"),
+                None => println!("{}", command_manager.command(&dc.name)?.dump_source()),
+            }
+            Ok(())
+        }
+        Commands::Docs(docs) => {
+            let command_manager = create_command_manager(&args.extra_command_path)?;
+            cli::docs::generate(&command_manager, &docs.output, docs.man_pages)
+        }
+        Commands::RenderScript(render) => {
+            // The script goes to stdout, so keep the printer quiet
+            let mut builder = cli::context::ContextBuilder::new(
+                Printer::new(&cli::printer::LogLevel::Off, true),
+                create_command_manager(&args.extra_command_path)?,
+            )
+            .timestamp(render.timestamp.clone())?;
+            if let Some(v) = &render.artifact_version {
+                builder = builder.version(v.clone())?;
+            }
+            let ctx = builder
+                .build()?
+                .create_placeholder_build_context(&render.command, &render.arguments)
+                .context("Failed to set up placeholder context")?;
+
+            print!(
+                "{}",
+                cli::scripts::render_script(
+                    &ctx,
+                    &render.command,
+                    &render.arguments,
+                    &render.phase
+                )?
+            );
+            Ok(())
+        }
+        Commands::ShowContext(show) => {
+            let snapshot = cli::context::ContextSnapshot::load(&show.artifacts_directory)?;
+            match show.format.serialize(&snapshot)? {
+                Some(output) => println!("{output}"),
+                None => print!("{snapshot}"),
+            }
             Ok(())
         }
         Commands::Initialize(init) => {
@@ -132,7 +132,7 @@ impl Printer {
     #[allow(unused)]
     fn pop_headline(&self) {
         let mut hls = self.0.headlines.borrow_mut();
-        assert!(hls.len() > 0);
+        assert!(!hls.is_empty());
         hls.pop();
     }
 
@@ -146,7 +146,7 @@ impl Printer {
         }
 
         let old_count = self.0.error_count.replace_with(|&mut v| v + 1);
-        if self.0.exit_on_error || old_count == std::i32::MAX / 2 {
+        if self.0.exit_on_error || old_count == i32::MAX / 2 {
             self.clear_line();
             std::process::exit(old_count + 1);
         }
@@ -189,6 +189,11 @@ impl Printer {
         self.print_status();
     }
 
+    /// The stack of status messages pushed, outermost first
+    pub fn status_stack(&self) -> Vec<String> {
+        self.0.status_stack.borrow().clone()
+    }
+
     #[allow(unused)]
     pub fn pop_status(&self) {
         let status = self.0.status_stack.borrow_mut().pop();
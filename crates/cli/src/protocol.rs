@@ -0,0 +1,274 @@
+// Copyright © Tobias Hunger <tobias.hunger@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The protocol the agent script uses to talk to the agent runner
+//!
+//! The agent script writes one JSON object per line into a named pipe that
+//! the agent runner bind-mounts into the container. Free-form values are
+//! hex encoded, so they may contain arbitrary bytes. Stdout and stderr of
+//! the agent script are for display only.
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+
+/// Where the protocol channel is mounted inside the container
+pub(crate) const CHANNEL: &str = "/tmp/clrm/channel";
+
+fn from_hex<'de, D>(deserializer: D) -> Result<OsString, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let hex: String = serde::Deserialize::deserialize(deserializer)?;
+    decode_hex(&hex).map_err(serde::de::Error::custom)
+}
+
+fn from_hex_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    from_hex(deserializer)?
+        .into_string()
+        .map_err(|v| serde::de::Error::custom(format!("Value {v:?} is not valid UTF-8")))
+}
+
+pub(crate) fn decode_hex(hex: &str) -> anyhow::Result<OsString> {
+    if hex.len() % 2 != 0 {
+        return Err(anyhow!("Hex value {hex:?} has an odd number of digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| anyhow!("Hex value {hex:?} contains invalid digits"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(OsString::from_vec)
+}
+
+/// How important a logged message is
+#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    Warn,
+    Info,
+    Debug,
+}
+
+/// A message sent by the agent script
+#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(tag = "verb", rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+pub(crate) enum Message {
+    /// Set a variable
+    Set {
+        name: String,
+        #[serde(deserialize_with = "from_hex")]
+        value: OsString,
+    },
+    /// Set a read-only variable
+    SetRo {
+        name: String,
+        #[serde(deserialize_with = "from_hex")]
+        value: OsString,
+    },
+    /// Add an item to a list variable
+    Append {
+        name: String,
+        #[serde(deserialize_with = "from_hex")]
+        value: OsString,
+    },
+    /// Remove an item from a list variable
+    Remove {
+        name: String,
+        #[serde(deserialize_with = "from_hex")]
+        value: OsString,
+    },
+    /// Remove a variable
+    Unset { name: String },
+    /// Build `command` and store its artifacts directory in `name`
+    AddDependency {
+        name: String,
+        #[serde(deserialize_with = "from_hex_string")]
+        command: String,
+    },
+    /// Report a new status
+    Status {
+        #[serde(deserialize_with = "from_hex")]
+        message: OsString,
+    },
+    /// Enter a nested status
+    Push {
+        #[serde(deserialize_with = "from_hex")]
+        message: OsString,
+    },
+    /// Leave a nested status
+    Pop,
+    /// Log a message
+    Log {
+        level: LogLevel,
+        #[serde(deserialize_with = "from_hex")]
+        message: OsString,
+    },
+}
+
+impl Message {
+    /// Parse one line received on the protocol channel
+    pub(crate) fn parse(line: &str) -> anyhow::Result<Self> {
+        serde_json::from_str(line).context(format!("Invalid agent protocol message {line:?}"))
+    }
+}
+
+/// The host side of the protocol channel
+pub(crate) struct Channel {
+    receiver: tokio::net::unix::pipe::Receiver,
+    pending: Vec<u8>,
+}
+
+impl Channel {
+    /// Create the named pipe at `path` and open it for reading
+    pub(crate) fn create(path: &Path) -> anyhow::Result<Self> {
+        if path.exists() {
+            std::fs::remove_file(path).context(format!("Failed to remove stale {path:?}"))?;
+        }
+        nix::unistd::mkfifo(
+            path,
+            nix::sys::stat::Mode::S_IRUSR | nix::sys::stat::Mode::S_IWUSR,
+        )
+        .context(format!("Failed to create protocol channel {path:?}"))?;
+        // Keep a writer open ourselves, so the channel never reports EOF
+        // while the agent script is starting up or between writes
+        let receiver = tokio::net::unix::pipe::OpenOptions::new()
+            .read_write(true)
+            .open_receiver(path)
+            .context(format!("Failed to open protocol channel {path:?}"))?;
+        Ok(Self {
+            receiver,
+            pending: vec![],
+        })
+    }
+
+    fn take_line(&mut self) -> Option<String> {
+        let end = self.pending.iter().position(|b| *b == b'\n')?;
+        let line: Vec<_> = self.pending.drain(..=end).collect();
+        Some(String::from_utf8_lossy(&line[..end]).to_string())
+    }
+
+    fn read_available(&mut self) -> anyhow::Result<()> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.receiver.try_read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(n) => self.pending.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e).context("Failed to read from protocol channel"),
+            }
+        }
+    }
+
+    /// Wait for the next line. This is cancel safe
+    pub(crate) async fn next_line(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(line) = self.take_line() {
+                return Ok(line);
+            }
+            self.receiver
+                .readable()
+                .await
+                .context("Failed to wait for the protocol channel")?;
+            self.read_available()?;
+        }
+    }
+
+    /// The lines that arrived but were not picked up yet
+    pub(crate) fn drain(&mut self) -> anyhow::Result<Vec<String>> {
+        // Read directly: The readiness tokio caches is stale once nobody
+        // waits for the channel anymore
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&self.receiver);
+        let mut buffer = [0; 4096];
+        loop {
+            match nix::unistd::read(fd, &mut buffer) {
+                Ok(0) | Err(nix::errno::Errno::EAGAIN) => break,
+                Ok(n) => self.pending.extend_from_slice(&buffer[..n]),
+                Err(nix::errno::Errno::EINTR) => {}
+                Err(e) => return Err(e).context("Failed to read from protocol channel"),
+            }
+        }
+        Ok(std::iter::from_fn(|| self.take_line()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Message::parse(r#"{"verb":"SET","name":"FOO","value":"610a22ff"}"#).unwrap(),
+            Message::Set {
+                name: "FOO".to_string(),
+                value: OsString::from_vec(vec![b'a', b'\n', b'"', 0xff]),
+            }
+        );
+        assert_eq!(
+            Message::parse(r#"{"verb":"SET_RO","name":"FOO","value":""}"#).unwrap(),
+            Message::SetRo {
+                name: "FOO".to_string(),
+                value: OsString::new(),
+            }
+        );
+        assert_eq!(
+            Message::parse(r#"{"verb":"ADD_DEPENDENCY","name":"FOO","command":"626172"}"#).unwrap(),
+            Message::AddDependency {
+                name: "FOO".to_string(),
+                command: "bar".to_string(),
+            }
+        );
+        assert_eq!(Message::parse(r#"{"verb":"POP"}"#).unwrap(), Message::Pop);
+        assert_eq!(
+            Message::parse(r#"{"verb":"REMOVE","name":"FOO","value":"6f6b"}"#).unwrap(),
+            Message::Remove {
+                name: "FOO".to_string(),
+                value: OsString::from("ok"),
+            }
+        );
+        assert_eq!(
+            Message::parse(r#"{"verb":"UNSET","name":"FOO"}"#).unwrap(),
+            Message::Unset {
+                name: "FOO".to_string(),
+            }
+        );
+        assert_eq!(
+            Message::parse(r#"{"verb":"LOG","level":"warn","message":"6f6b"}"#).unwrap(),
+            Message::Log {
+                level: LogLevel::Warn,
+                message: OsString::from("ok"),
+            }
+        );
+
+        assert!(Message::parse(r#"{"verb":"XXX"}"#).is_err());
+        assert!(
+            Message::parse(r#"{"verb":"ADD_DEPENDENCY","name":"FOO","command":"ff"}"#).is_err()
+        );
+        assert!(Message::parse(r#"{"verb":"STATUS","message":"6"}"#).is_err());
+        assert!(Message::parse(r#"{"verb":"STATUS","message":"zz"}"#).is_err());
+        assert!(Message::parse(r#"{"verb":"LOG","level":"error","message":""}"#).is_err());
+        assert!(Message::parse("SET FOO=bar").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("channel");
+        let mut channel = Channel::create(&path).unwrap();
+
+        std::fs::write(&path, "first\nsecond\nthi").unwrap();
+        assert_eq!(channel.next_line().await.unwrap(), "first");
+        std::fs::write(&path, "rd\n").unwrap();
+        assert_eq!(channel.drain().unwrap(), vec!["second", "third"]);
+        assert!(channel.drain().unwrap().is_empty());
+    }
+}
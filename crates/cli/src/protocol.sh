@@ -0,0 +1,93 @@
+#!/usr/bin/sh -e
+# The agent protocol: Functions talking to the agent runner
+#
+# Messages are JSON objects, one per line, written to file descriptor 3.
+# Free-form values are hex encoded.
+
+__hex() {
+	printf '%s' "${*}" | "${BUSYBOX}" od -An -v -tx1 | "${BUSYBOX}" tr -d ' \n'
+}
+
+__send() {
+	printf '%s\n' "${*}" >&3
+}
+
+status() {
+	__send "{\"verb\":\"STATUS\",\"message\":\"$(__hex "${1}")\"}"
+}
+
+push_status() {
+	__send "{\"verb\":\"PUSH\",\"message\":\"$(__hex "${1}")\"}"
+}
+
+pop_status() {
+	__send "{\"verb\":\"POP\"}"
+}
+
+__log() {
+	__send "{\"verb\":\"LOG\",\"level\":\"${1}\",\"message\":\"$(__hex "${2}")\"}"
+}
+
+log_warn() {
+	__log warn "${*}"
+}
+
+log_info() {
+	__log info "${*}"
+}
+
+log_debug() {
+	__log debug "${*}"
+}
+
+export_constant() {
+	key="${1}"
+	shift
+
+	eval "${key}=\"\${*}\""
+	readonly "${key}"
+	__context_variables="${__context_variables} ${key}"
+	__send "{\"verb\":\"SET_RO\",\"name\":\"${key}\",\"value\":\"$(__hex "${*}")\"}"
+}
+
+export_var() {
+	key="${1}"
+	shift
+
+	__send "{\"verb\":\"SET\",\"name\":\"${key}\",\"value\":\"$(__hex "${*}")\"}"
+}
+
+append_var() {
+	key="${1}"
+	shift
+
+	for value in "${@}"; do
+		__send "{\"verb\":\"APPEND\",\"name\":\"${key}\",\"value\":\"$(__hex "${value}")\"}"
+	done
+}
+
+remove_var() {
+	key="${1}"
+	shift
+
+	for value in "${@}"; do
+		__send "{\"verb\":\"REMOVE\",\"name\":\"${key}\",\"value\":\"$(__hex "${value}")\"}"
+	done
+}
+
+unset_var() {
+	__send "{\"verb\":\"UNSET\",\"name\":\"${1}\"}"
+}
+
+add_dependency() {
+	key="${1}"
+	shift
+
+	eval "${key}=\"\${*}\""
+	__send "{\"verb\":\"ADD_DEPENDENCY\",\"name\":\"${key}\",\"command\":\"$(__hex "${*}")\"}"
+}
+
+error() {
+	echo "Error in Agent script: ${*}"
+	exit 1
+}
@@ -1,12 +1,14 @@
 // Copyright © Tobias Hunger <tobiias.hunger@gmail.com>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::ffi::OsStr;
 use std::io::Write;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 
-use crate::commands::CommandName;
+use crate::commands::{Command, CommandManager, CommandName, DataDirectory, Input, InputType};
 use crate::context::BuildContext;
 
 struct Section {
@@ -48,22 +50,209 @@ impl Section {
     }
 }
 
-fn escape(input: &str) -> String {
-    let mut result = String::new();
+fn quote(input: &str) -> String {
+    format!("'{}'", input.replace('\'', "'\\''"))
+}
+
+/// Where a line of the agent script came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The command the line belongs to
+    pub command: CommandName,
+    /// The shell function the line is in
+    pub function: String,
+    /// The file the command was defined in
+    pub source_location: String,
+    /// The line in `source_location`, if known
+    pub line: Option<usize>,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} ({}:{line})", self.function, self.source_location),
+            None => write!(f, "{} ({})", self.function, self.source_location),
+        }
+    }
+}
+
+/// A range of agent script lines coming from one place
+#[derive(Clone, Debug)]
+struct SourceRange {
+    /// The first line of the range: 0-based and relative while the script
+    /// is assembled, 1-based and absolute in a `SourceMap`
+    first_line: usize,
+    line_count: usize,
+    location: SourceLocation,
+}
+
+/// Maps lines of the agent script back to the `Command`s they came from
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    /// Function ranges, followed by the ranges of the script snippets in them
+    ranges: Vec<SourceRange>,
+}
+
+impl SourceMap {
+    /// The origin of the 1-based `line` of the agent script
+    pub fn lookup(&self, line: usize) -> Option<SourceLocation> {
+        self.ranges
+            .iter()
+            .rev()
+            .find(|r| (r.first_line..r.first_line + r.line_count).contains(&line))
+            .map(|r| {
+                let mut location = r.location.clone();
+                location.line = location.line.map(|l| l + line - r.first_line);
+                location
+            })
+    }
+
+    /// The definition of the shell function `function`
+    pub fn function(&self, function: &str) -> Option<SourceLocation> {
+        self.ranges
+            .iter()
+            .find(|r| r.location.function == function)
+            .map(|r| r.location.clone())
+    }
+}
+
+fn line_count(text: &str) -> usize {
+    text.matches('\n').count()
+}
+
+/// Move `ranges` down by `offset` lines and add them to `target`
+fn extend_ranges(target: &mut Vec<SourceRange>, ranges: Vec<SourceRange>, offset: usize) {
+    target.extend(ranges.into_iter().map(|mut r| {
+        r.first_line += offset;
+        r
+    }));
+}
+
+/// Bodies run by an interpreter, keyed by function name
+type Bodies = Vec<(String, String)>;
+
+/// Shell code generated for `Command`s
+#[derive(Debug, Default)]
+struct Definition {
+    text: String,
+    /// Where the lines of `text` came from, relative to its start
+    ranges: Vec<SourceRange>,
+    bodies: Bodies,
+}
+
+impl Definition {
+    fn append(&mut self, other: Definition) {
+        extend_ranges(&mut self.ranges, other.ranges, line_count(&self.text));
+        self.text.push_str(&other.text);
+        self.bodies.extend(other.bodies);
+    }
+}
+
+/// Remove the indentation common to all non-empty lines of `text`
+fn dedent(text: &str) -> String {
+    let indentation = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or_default();
+    text.lines()
+        .map(|l| l.get(indentation..).unwrap_or_else(|| l.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The inputs of `cmd` as arguments to pass on to other commands
+///
+/// The values of a variadic input are the positional parameters left over
+/// once all other inputs are shifted off.
+fn input_arguments(cmd: &Command) -> String {
+    cmd.inputs()
+        .map(|i| {
+            if i.variadic() {
+                " \"${@}\"".to_string()
+            } else {
+                format!(" \"${{{}}}\"", i.name())
+            }
+        })
+        .collect()
+}
+
+/// The line `snippet` starts on in the definition of `cmd`
+fn snippet_line(cmd: &Command, snippet: &str) -> Option<usize> {
+    let source = cmd.dump_source();
+    let first_line = snippet.lines().next().filter(|l| !l.trim().is_empty())?;
+    let position = source.find(snippet).or_else(|| source.find(first_line))?;
+    Some(line_count(&source[..position]) + 1)
+}
+
+/// Append `input` to the `printf` format string `format`
+fn push_printf_format(format: &mut String, input: &str) {
     for c in input.chars() {
         match c {
-            '\\' => {
-                result.push(c);
-                result.push(c);
+            '\\' => format.push_str("\\\\"),
+            '%' => format.push_str("%%"),
+            '\'' => format.push_str("'\\''"),
+            _ => format.push(c),
+        }
+    }
+}
+
+/// A shell snippet setting the variable `name` to exactly `value`
+///
+/// Values are single-quoted, so nothing in them gets expanded. Values that
+/// are not valid UTF-8 are produced by `printf` with the offending bytes
+/// written as octal escapes.
+fn shell_assignment(name: &str, value: &OsStr) -> anyhow::Result<String> {
+    let bytes = value.as_bytes();
+    if bytes.contains(&0) {
+        return Err(anyhow::anyhow!(
+            "Value of {name} contains a NUL byte, which can not be passed to the shell"
+        ));
+    }
+    if let Some(value) = value.to_str() {
+        return Ok(format!("{name}={}\n", quote(value)));
+    }
+
+    let mut format = String::new();
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                push_printf_format(&mut format, valid);
+                break;
             }
-            '"' => {
-                result.push('\\');
-                result.push(c);
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                push_printf_format(
+                    &mut format,
+                    std::str::from_utf8(valid).expect("Was validated"),
+                );
+                let invalid_len = e.error_len().unwrap_or(invalid.len());
+                for b in &invalid[..invalid_len] {
+                    format.push_str(&format!("\\{b:03o}"));
+                }
+                rest = &invalid[invalid_len..];
             }
-            _ => result.push(c),
         }
     }
-    result
+
+    // Command substitution strips trailing newlines: Protect them with a
+    // sentinel character that gets removed again afterwards
+    Ok(format!(
+        "{name}=\"$(printf '{format}_')\"\n{name}=\"${{{name}%_}}\"\n"
+    ))
+}
+
+/// The shell code shared by all agent scripts
+pub(crate) fn header_script() -> String {
+    [include_str!("protocol.sh"), include_str!("header.sh")].concat()
+}
+
+fn script_add_protocol() -> Section {
+    let mut section = Section::new("agent protocol");
+    section.push_str(include_str!("protocol.sh"));
+    section
 }
 
 fn script_add_header() -> Section {
@@ -72,48 +261,385 @@ fn script_add_header() -> Section {
     section
 }
 
+/// A script making the agent protocol available to bodies run by an
+/// interpreter
+///
+/// Bodies call it as `"${CLRM_PROTOCOL}" <function> <arguments>...` or
+/// write protocol messages to file descriptor 3 themselves. Only the agent
+/// runner learns about variables exported that way, so they are visible to
+/// shell code in later phases only.
+fn protocol_helper_script(busybox: &str, channel: &str) -> String {
+    format!(
+        "#!{busybox} sh\nset -e\nBUSYBOX=\"{busybox}\"\nexec 3>\"{channel}\"\n{}\n\
+case \"${{1}}\" in\n\
+\tstatus|push_status|pop_status|log_warn|log_info|log_debug|export_constant|export_var|append_var|remove_var|unset_var|add_dependency|error) ;;\n\
+\t*) error \"Unknown agent protocol function ${{1}}\" ;;\n\
+esac\n\
+\"${{@}}\"\n",
+        include_str!("protocol.sh")
+            .split_once('\n')
+            .map(|(_, rest)| rest)
+            .unwrap_or_default()
+            .trim_end()
+    )
+}
+
+/// Record the shell variables in `names` as coming from the build context,
+/// so they get exported to bodies run by an interpreter
+fn context_variables_assignment(names: &[String]) -> String {
+    if names.is_empty() {
+        return String::new();
+    }
+    format!(
+        "__context_variables=\"${{__context_variables}} {}\"\n",
+        names.join(" ")
+    )
+}
+
 fn script_add_phase_definitions() -> Section {
     let mut section = Section::new("phase definitions");
+    let mut names = vec![];
     for p in crate::Phases::iter().map(|p| p.to_string()) {
         let pu = p.to_uppercase();
         section.push_str(&format!("PHASE_{pu}=\"{p}\"\nreadonly PHASE_{pu}\n"));
+        names.push(format!("PHASE_{pu}"));
     }
+    section.push_str(&context_variables_assignment(&names));
     section
 }
 
-fn script_add_command_definitions(ctx: &BuildContext) -> anyhow::Result<Section> {
-    let mut section = Section::new("command definition");
+fn input_check(command: &CommandName, input: &Input) -> Option<String> {
+    let name = input.name();
+    match input.input_type() {
+        InputType::String => None,
+        InputType::Bool => Some(format!("check_input_bool {command} {name}")),
+        InputType::Integer => Some(format!("check_input_integer {command} {name}")),
+        InputType::Path => Some(format!("check_input_path {command} {name}")),
+        InputType::Enum => Some(format!(
+            "check_input_enum {command} {name} {}",
+            input
+                .values()
+                .iter()
+                .map(|v| quote(v))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )),
+        InputType::Regex => Some(format!(
+            "check_input_regex {command} {name} {}",
+            quote(input.pattern().unwrap_or_default())
+        )),
+    }
+}
+
+/// Where command data directories are mounted inside the container
+pub(crate) const DATA_DIRECTORY: &str = "/tmp/clrm/data";
+/// Where bodies run by an interpreter are mounted inside the container
+pub(crate) const BODIES_DIRECTORY: &str = "/tmp/clrm/bodies";
+/// Where the agent protocol helper is mounted inside the container
+pub(crate) const PROTOCOL_HELPER: &str = "/tmp/clrm/protocol";
+/// Where busybox is mounted inside the container
+const BUSYBOX: &str = "/tmp/clrm/busybox";
+
+/// The name of the shell function holding the definition of `name` that got
+/// shadowed `depth` times
+///
+/// Scripts call the definition they shadow as `super_<name>`.
+fn function_name(name: &CommandName, depth: usize) -> String {
+    format!("{}{name}", "super_".repeat(depth))
+}
+
+/// Make `super_<name>` calls in `body` refer to the definition shadowed by
+/// the one at `depth`
+fn rename_super_calls(body: &str, name: &CommandName, depth: usize) -> String {
+    let pattern = function_name(name, 1);
+    let replacement = function_name(name, depth + 1);
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut result = String::new();
+    let mut rest = body;
+    while let Some(pos) = rest.find(&pattern) {
+        let end = pos + pattern.len();
+        let starts_word = !rest[..pos].ends_with(is_name_char);
+        let ends_word = !rest[end..].starts_with(is_name_char);
+        result.push_str(&rest[..pos]);
+        if starts_word && ends_word {
+            result.push_str(&replacement);
+        } else {
+            result.push_str(&pattern);
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The shell function definitions of the `Command` `name` in `phase`
+///
+/// This includes all definitions shadowed by `cmd`.
+pub fn command_definition(
+    manager: &CommandManager,
+    name: &CommandName,
+    cmd: &Command,
+    phase: &crate::Phases,
+) -> String {
+    mapped_command_definition(manager, name, cmd, phase).text
+}
+
+fn mapped_command_definition(
+    manager: &CommandManager,
+    name: &CommandName,
+    cmd: &Command,
+    phase: &crate::Phases,
+) -> Definition {
+    let mut result = Definition::default();
+    let mut current = Some(cmd);
+    let mut depth = 0;
+    while let Some(c) = current {
+        let attached = if depth == 0 {
+            manager.attached_to(name)
+        } else {
+            &[]
+        };
+        result.append(function_definition(name, c, depth, attached, phase));
+        current = c.overwritten();
+        depth += 1;
+    }
+    result
+}
+
+/// The `Command`s in `manager` that `start_command` may end up calling,
+/// including `start_command` itself
+///
+/// These are all `Command`s as soon as one call can not be resolved
+/// statically.
+fn reachable_commands<'a>(
+    manager: &'a CommandManager,
+    start_command: &CommandName,
+) -> impl Iterator<Item = (&'a CommandName, &'a Command)> {
+    let graph = manager.call_graph();
+    let reachable = graph
+        .is_transparent_from(start_command)
+        .then(|| graph.reachable_from(start_command));
+    manager
+        .commands()
+        .filter(move |(name, _)| reachable.as_ref().map_or(true, |r| r.contains(*name)))
+}
+
+/// The data directories of all commands reachable from `start_command`
+/// (including shadowed definitions), keyed by the name of the function
+/// owning them
+pub fn data_directories<'a>(
+    manager: &'a CommandManager,
+    start_command: &CommandName,
+) -> Vec<(String, &'a DataDirectory)> {
+    let mut result = vec![];
+    for (name, cmd) in reachable_commands(manager, start_command) {
+        let mut current = Some(cmd);
+        let mut depth = 0;
+        while let Some(c) = current {
+            if let Some(data) = c.data_directory() {
+                result.push((function_name(name, depth), data));
+            }
+            current = c.overwritten();
+            depth += 1;
+        }
+    }
+    result
+}
 
-    for (name, cmd) in ctx.command_manager().commands() {
-        section.push_str(&format!("{name}() {{\n"));
-        section.push_str(&format!("    push_status \"{name}\"\n"));
-        for i in cmd.inputs() {
+fn function_definition(
+    name: &CommandName,
+    cmd: &Command,
+    depth: usize,
+    attached: &[CommandName],
+    phase: &crate::Phases,
+) -> Definition {
+    let mut result = String::new();
+    let function = function_name(name, depth);
+    let location = |line| SourceLocation {
+        command: name.clone(),
+        function: function.clone(),
+        source_location: cmd.source_location().to_string(),
+        line,
+    };
+    let mut ranges = vec![];
+    let mut bodies = vec![];
+
+    result.push_str(&format!("{function}() {{\n"));
+    result.push_str(&format!("    push_status \"{function}\"\n"));
+    if cmd.data_directory().is_some() {
+        result.push_str(&format!(
+            "    local COMMAND_DATA_DIR=\"{DATA_DIRECTORY}/{}\"\n",
+            function_name(name, depth)
+        ));
+    }
+    for i in cmd.inputs() {
+        if i.variadic() {
+            if !i.optional() {
+                result.push_str(&format!(
+                    "    [ \"${{#}}\" -gt 0 ] || error \"{name}: Input \\\"{}\\\" expects at least one value\"\n",
+                    i.name()
+                ));
+            }
+        } else if let Some(default) = i.default_value() {
+            result.push_str(&format!(
+                "    if [ \"${{#}}\" -gt 0 ]; then {0}=\"${{1}}\"; shift; else {0}={1}; fi\n",
+                i.name(),
+                quote(default)
+            ));
+        } else {
             let optional_shift = if i.optional() { " || true" } else { "" };
-            section.push_str(&format!(
+            result.push_str(&format!(
                 "    {}=\"${{1}}\"; shift{optional_shift}\n",
                 i.name()
             ));
         }
-        section.push_str(&format!("\n{}\n    pop_status\n}}\n\n", cmd.script));
     }
+    for i in cmd.inputs() {
+        if let Some(check) = input_check(name, i) {
+            if i.optional() {
+                result.push_str(&format!("    [ -z \"${{{}}}\" ] || {check}\n", i.name()));
+            } else {
+                result.push_str(&format!("    {check}\n"));
+            }
+        }
+    }
+    if !cmd.distributions().is_empty() {
+        result.push_str(&format!(
+            "    check_distribution {name} {}\n",
+            cmd.distributions()
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ));
+    }
+    let phase_script = cmd.phase_script(phase);
+    let snippets = [
+        phase_script.map(|ps| ps.setup.as_str()),
+        Some(cmd.script.as_str()),
+        phase_script.map(|ps| ps.script.as_str()),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|s| s.trim_end())
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>();
+    let body = snippets.join("\n\n");
+    // Files get installed after the body ran, so the body can not depend on
+    // them, but also can not overwrite them by accident
+    let mut files = String::new();
+    for file in cmd.files().iter().filter(|_| cmd.runs_in_phase(phase)) {
+        let path = quote(file.path());
+        if file.phase() == phase {
+            let source = match (file.content(), file.source()) {
+                (Some(content), _) => format!("--content {}", quote(content)),
+                (None, Some(source)) => format!("\"${{COMMAND_DATA_DIR}}\"/{}", quote(source)),
+                (None, None) => unreachable!("Files are validated"),
+            };
+            files.push_str(&format!(
+                "    install_file {path} {:o} {} {source}\n",
+                file.mode(),
+                quote(file.owner())
+            ));
+        }
+        if *phase == crate::Phases::Test {
+            files.push_str(&format!("    check_file {path} {:o}\n", file.mode()));
+        }
+    }
+    if let Some(interpreter) = cmd.interpreter().filter(|_| !body.is_empty()) {
+        if cmd.runs_in_phase(phase) {
+            let data_directory = if cmd.data_directory().is_some() {
+                " COMMAND_DATA_DIR=\"${COMMAND_DATA_DIR}\""
+            } else {
+                ""
+            };
+            // The interpreter sees the build context in its environment
+            result.push_str(&format!(
+                "\n    (\n        export ${{__context_variables}}\n        CLRM_PROTOCOL=\"{PROTOCOL_HELPER}\"{data_directory} \\\n            {} \"{BODIES_DIRECTORY}/{function}\"{}\n    )\n\n",
+                quote(interpreter),
+                input_arguments(cmd)
+            ));
+            let body = snippets
+                .iter()
+                .map(|s| dedent(s))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            bodies.push((function.clone(), format!("{body}\n")));
+        }
+    } else if !body.is_empty() && cmd.runs_in_phase(phase) {
+        let body = if depth > 0 {
+            rename_super_calls(&body, name, depth)
+        } else {
+            body
+        };
+        let mut first_line = line_count(&result) + 1;
+        for snippet in snippets {
+            let snippet_lines = snippet.lines().count();
+            ranges.push(SourceRange {
+                first_line,
+                line_count: snippet_lines,
+                location: location(snippet_line(cmd, snippet)),
+            });
+            first_line += snippet_lines + 1;
+        }
+        result.push_str(&format!("\n{body}\n\n"));
+    }
+    result.push_str(&files);
+    if !attached.is_empty() {
+        // Pass on all inputs of the hook
+        let arguments = input_arguments(cmd);
+        for a in attached {
+            result.push_str(&format!("    {a}{arguments}\n"));
+        }
+        result.push('\n');
+    }
+    result.push_str("    pop_status\n}\n\n");
 
-    Ok(section)
+    ranges.insert(
+        0,
+        SourceRange {
+            first_line: 0,
+            line_count: line_count(&result),
+            location: location(None),
+        },
+    );
+    Definition {
+        text: result,
+        ranges,
+        bodies,
+    }
+}
+
+fn script_add_command_definitions(
+    ctx: &BuildContext,
+    start_command: &CommandName,
+    phase: &crate::Phases,
+) -> anyhow::Result<(Section, Definition)> {
+    let mut section = Section::new("command definition");
+    let mut definitions = Definition::default();
+
+    let manager = ctx.command_manager();
+    for (name, cmd) in reachable_commands(manager, start_command) {
+        definitions.append(mapped_command_definition(manager, name, cmd, phase));
+    }
+    section.push_str(&definitions.text);
+
+    Ok((section, definitions))
 }
 
-fn script_add_system_environment(ctx: &BuildContext) -> Section {
+fn script_add_system_environment(ctx: &BuildContext) -> anyhow::Result<Section> {
     let mut section = Section::new("system environment");
+    let mut names = vec![];
     for ce in ctx.iter().filter(|ce| !ce.is_internal) {
-        let value = escape(&ce.value);
+        section.push_str(&shell_assignment(&ce.name, &ce.value.to_os_string())?);
         if ce.is_read_only {
-            section.push_str(&format!(
-                "{}=\"{}\"\nreadonly {}\n",
-                ce.name, value, ce.name
-            ));
-        } else {
-            section.push_str(&format!("{}=\"{}\"\n", ce.name, value));
+            section.push_str(&format!("readonly {}\n", ce.name));
         }
+        names.push(ce.name);
     }
-    section
+    section.push_str(&context_variables_assignment(&names));
+    Ok(section)
 }
 
 fn script_add_pre_command() -> Section {
@@ -122,9 +648,12 @@ fn script_add_pre_command() -> Section {
     section
 }
 
-fn script_add_command(start_command: &CommandName) -> Section {
+fn script_add_command(start_command: &CommandName, arguments: &[String]) -> Section {
     let mut section = Section::new("command");
     section.push_str(&start_command.to_string());
+    for a in arguments {
+        section.push_str(&format!(" {}", quote(a)));
+    }
     section
 }
 
@@ -134,28 +663,118 @@ fn script_add_footer() -> Section {
     section
 }
 
-pub fn create_script(ctx: &BuildContext, start_command: &CommandName) -> anyhow::Result<PathBuf> {
-    let p = ctx.printer();
-    let script_path = ctx.scratch_directory().join("script.sh");
+/// The files making up the agent script on the host
+pub struct AgentScript {
+    /// The script run by the agent
+    pub script: PathBuf,
+    /// The directory holding bodies run by an interpreter
+    pub bodies_directory: PathBuf,
+    /// The agent protocol helper for bodies run by an interpreter
+    pub protocol_helper: PathBuf,
+    /// Where the lines of `script` came from
+    pub source_map: SourceMap,
+}
 
+/// The agent script running `start_command` with `arguments` in `phase`,
+/// together with the map of where its lines came from and the bodies run
+/// by an interpreter
+fn assemble_script(
+    ctx: &BuildContext,
+    start_command: &CommandName,
+    arguments: &[String],
+    phase: &crate::Phases,
+) -> anyhow::Result<(String, SourceMap, Bodies)> {
     let mut script_contents = String::from("#!/bin/sh -e\n");
 
     if ctx.check_debug_option(&crate::DebugOptions::TraceAgentScript) {
         script_contents += "set -x\n";
     }
 
+    script_contents += &script_add_protocol().extract();
     script_contents += &script_add_header().extract();
     script_contents += &script_add_phase_definitions().extract();
-    script_contents += &script_add_command_definitions(ctx)?.extract();
-    script_contents += &script_add_system_environment(ctx).extract();
+
+    let (section, definitions) = script_add_command_definitions(ctx, start_command, phase)?;
+    let mut source_map = SourceMap::default();
+    // Skip the section start marker and make lines 1-based:
+    extend_ranges(
+        &mut source_map.ranges,
+        definitions.ranges,
+        line_count(&script_contents) + 2,
+    );
+    script_contents += &section.extract();
+
+    script_contents += &script_add_system_environment(ctx)?.extract();
     script_contents += &script_add_pre_command().extract();
-    script_contents += &script_add_command(start_command).extract();
+    script_contents += &script_add_command(start_command, arguments).extract();
     script_contents += &script_add_footer().extract();
 
-    let mut output = std::fs::File::create(&script_path)
-        .context(format!("Failed to write agent script file {script_path:?}"))?;
-    write!(output, "{script_contents}")
-        .context(format!("Failed to write agent script into {script_path:?}"))?;
+    Ok((script_contents, source_map, definitions.bodies))
+}
+
+/// The agent script running `start_command` with `arguments` in `phase`
+pub fn render_script(
+    ctx: &BuildContext,
+    start_command: &CommandName,
+    arguments: &[String],
+    phase: &crate::Phases,
+) -> anyhow::Result<String> {
+    Ok(assemble_script(ctx, start_command, arguments, phase)?.0)
+}
+
+/// The agent script running `start_command` in `phase`, together with the
+/// map of where its lines came from
+pub(crate) fn render_mapped_script(
+    ctx: &BuildContext,
+    start_command: &CommandName,
+    phase: &crate::Phases,
+) -> anyhow::Result<(String, SourceMap)> {
+    let (script, source_map, _) = assemble_script(ctx, start_command, &[], phase)?;
+    Ok((script, source_map))
+}
+
+fn write_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut output =
+        std::fs::File::create(path).context(format!("Failed to create file {path:?}"))?;
+    write!(output, "{contents}").context(format!("Failed to write into {path:?}"))
+}
+
+/// Write the agent script running `start_command` in `phase` into the
+/// scratch directory
+pub fn create_script(
+    ctx: &BuildContext,
+    start_command: &CommandName,
+    phase: &crate::Phases,
+) -> anyhow::Result<AgentScript> {
+    let p = ctx.printer();
+    let script_path = ctx.scratch_directory().join("script.sh");
+    let bodies_directory = ctx.scratch_directory().join("bodies");
+    let protocol_helper = ctx.scratch_directory().join("protocol");
+
+    let (script_contents, source_map, bodies) = assemble_script(ctx, start_command, &[], phase)?;
+
+    write_file(&script_path, &script_contents).context("Failed to write agent script")?;
+
+    if bodies_directory.exists() {
+        std::fs::remove_dir_all(&bodies_directory)
+            .context(format!("Failed to clean up {bodies_directory:?}"))?;
+    }
+    std::fs::create_dir(&bodies_directory)
+        .context(format!("Failed to create {bodies_directory:?}"))?;
+    for (function, body) in &bodies {
+        write_file(&bodies_directory.join(function), body)
+            .context(format!("Failed to write body of {function}"))?;
+    }
+    write_file(
+        &protocol_helper,
+        &protocol_helper_script(BUSYBOX, crate::protocol::CHANNEL),
+    )
+    .context("Failed to write agent protocol helper")?;
+    std::fs::set_permissions(
+        &protocol_helper,
+        std::os::unix::fs::PermissionsExt::from_mode(0o755),
+    )
+    .context("Failed to make agent protocol helper executable")?;
 
     p.trace(&format!("Full agent script at {script_path:?}"));
 
@@ -163,29 +782,719 @@ pub fn create_script(ctx: &BuildContext, start_command: &CommandName) -> anyhow:
         p.debug(&format!("Script contents is:\n{script_contents}"));
     }
 
-    Ok(script_path)
+    Ok(AgentScript {
+        script: script_path,
+        bodies_directory,
+        protocol_helper,
+        source_map,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn shell_escape(input: &str, expected: &str) {
-        let result = escape(input);
+    fn builtin_command_definitions(start_command: &str, phase: &crate::Phases) -> String {
+        let ctx = crate::context::ContextBuilder::new_test().build().unwrap();
+        let ctx = ctx.test_system();
+        let start_command = CommandName::parse_value(start_command).unwrap();
+        script_add_command_definitions(&ctx, &start_command, phase)
+            .unwrap()
+            .0
+            .extract()
+    }
+
+    #[test]
+    fn test_command_definitions_variadic() {
+        let definitions = builtin_command_definitions("pkg_kernel", &crate::Phases::Prepare);
+
+        assert!(!definitions.contains("packages="));
+        assert!(definitions.contains(
+            "    [ \"${#}\" -gt 0 ] || error \"add_packages: Input \\\"packages\\\" expects at least one value\"\n"
+        ));
+    }
+
+    #[test]
+    fn test_command_definitions_variadic_quoting() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("print_args.toml"),
+            r#"
+[command]
+inputs = ["first", { name = "rest", variadic = true }]
+script = """
+    printf '<%s>' "${first}" "${@}"
+"""
+"#,
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let name = CommandName::parse_value("print_args").unwrap();
+        let definition = command_definition(
+            &manager,
+            &name,
+            manager.command(&name).unwrap(),
+            &crate::Phases::Prepare,
+        );
+        let output = std::process::Command::new("/bin/sh")
+            .arg("-ec")
+            .arg(format!(
+                "push_status() {{ :; }}\npop_status() {{ :; }}\n{definition}print_args 'a b' 'c  d' '*'"
+            ))
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"<a b><c  d><*>");
+    }
+
+    #[test]
+    fn test_command_definitions_default() {
+        let definitions = builtin_command_definitions("pkg_kernel", &crate::Phases::Prepare);
+
+        assert!(definitions.contains(
+            "    if [ \"${#}\" -gt 0 ]; then package_name=\"${1}\"; shift; else package_name='linux'; fi\n"
+        ));
+    }
+
+    #[test]
+    fn test_command_definitions_phases() {
+        let prepare = builtin_command_definitions("strip_legal", &crate::Phases::Prepare);
+        let polish = builtin_command_definitions("strip_legal", &crate::Phases::Polish);
+
+        assert!(!prepare.contains("rm -rf /usr/share/licenses/*"));
+        assert!(polish.contains("rm -rf /usr/share/licenses/*"));
+    }
+
+    /// The definition of the builtin command `name` in `phase`
+    fn builtin_definition(name: &str, phase: &crate::Phases) -> String {
+        let manager = crate::commands::CommandManagerBuilder::default().build();
+        let name = CommandName::parse_value(name).unwrap();
+        command_definition(&manager, &name, manager.command(&name).unwrap(), phase)
+    }
+
+    /// Make sure all `parts` are in `text`, in the order given
+    fn assert_in_order(text: &str, parts: &[&str]) {
+        let mut rest = text;
+        for part in parts {
+            let Some(pos) = rest.find(part) else {
+                panic!("{part:?} missing or out of order in:\n{text}");
+            };
+            rest = &rest[pos + part.len()..];
+        }
+    }
+
+    #[test]
+    fn test_phase_tables_keep_statement_order() {
+        use crate::Phases;
+
+        let prepare = builtin_definition("define_system", &Phases::Prepare);
+        assert_in_order(
+            &prepare,
+            &[
+                "error \"Distribution already initialized.\"",
+                "export_constant MACHINE_ID",
+                "_distribution \"${distribution}\"",
+            ],
+        );
+        let polish = builtin_definition("define_system", &Phases::Polish);
+        assert_in_order(
+            &polish,
+            &[
+                "> /etc/machine.info",
+                "> /etc/machine-id",
+                "_distribution \"${distribution}\"",
+            ],
+        );
+        let install = builtin_definition("define_system", &Phases::Install);
+        assert!(install.contains("_distribution \"${distribution}\""));
+        assert!(!install.contains("export_constant"));
+        assert!(!install.contains("/etc/machine-id"));
+
+        let prepare = builtin_definition("_distribution", &Phases::Prepare);
+        assert_in_order(
+            &prepare,
+            &[
+                "unset_var PACKAGES",
+                "export_constant CLRM_BASE_DISTRIBUTION",
+                "bb_mkdir /efi",
+                "_distribution_arch",
+                "_ensure_development",
+                "export_verity_image",
+                "export_constant OS_RELEASE_IMAGE_ID",
+            ],
+        );
+        // The polish part runs between the distribution specific commands
+        // and the _ensure_* ones
+        let polish = builtin_definition("_distribution", &Phases::Polish);
+        assert_in_order(
+            &polish,
+            &[
+                "_distribution_serpentos",
+                "mv /opt /usr",
+                "rm -f",
+                "rm -rf",
+                "_ensure_development",
+                "_ensure_no_unused_shell_files",
+                "export_verity_image",
+            ],
+        );
+        assert!(!polish.contains("unset_var"));
+        assert!(!polish.contains("OS_RELEASE"));
+
+        let prepare = builtin_definition("add_packages", &Phases::Prepare);
+        assert_in_order(
+            &prepare,
+            &[
+                "assert_distribution_initialized",
+                "append_var PACKAGES \"${@}\"",
+            ],
+        );
+        let install = builtin_definition("add_packages", &Phases::Install);
+        assert!(!install.contains("append_var"));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(quote("foo bar"), "'foo bar'");
+        assert_eq!(quote("$foo `bar`"), "'$foo `bar`'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    /// Run the assignment of `value` in a shell and return what it sets
+    fn shell_round_trip(value: &OsStr) -> Vec<u8> {
+        let script = format!(
+            "{}printf '%s' \"${{FOO}}\"",
+            shell_assignment("FOO", value).unwrap()
+        );
+        let output = std::process::Command::new("/bin/sh")
+            .arg("-ec")
+            .arg(&script)
+            .current_dir(tempfile::tempdir().unwrap().path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "Script failed: {script:?}");
+        output.stdout
+    }
+
+    #[test]
+    fn test_shell_assignment() {
+        for value in [
+            "foobar 1, 2, 3, 4, XYZ # bar foo",
+            "",
+            "line 1\nline 2\n\n",
+            r#"foo "b\"a\"z" bar"#,
+            "$HOME ${HOME} `touch backtick` $(touch subshell) it's; & | > < * ? ~ % \\ \\n",
+        ] {
+            assert_eq!(shell_round_trip(OsStr::new(value)), value.as_bytes());
+        }
+        assert_eq!(
+            shell_assignment("FOO", OsStr::new("$(touch x)")).unwrap(),
+            "FOO='$(touch x)'\n"
+        );
+    }
+
+    #[test]
+    fn test_shell_assignment_non_utf8() {
+        let value: &[u8] = b"caf\xe9 100% 'quoted' \\n \xff\xfe\n\n";
+        let value = OsStr::from_bytes(value);
+        assert!(value.to_str().is_none());
+        assert_eq!(shell_round_trip(value), value.as_bytes());
+
+        assert!(shell_assignment("FOO", OsStr::from_bytes(b"a\0b")).is_err());
+    }
+
+    #[test]
+    fn test_rename_super_calls() {
+        let name = CommandName::parse_value("foo").unwrap();
+        assert_eq!(
+            rename_super_calls("super_foo\nsuper_foobar x_super_foo super_foo", &name, 1),
+            "super_super_foo\nsuper_foobar x_super_foo super_super_foo"
+        );
+    }
+
+    #[test]
+    fn test_command_definitions_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("extra_keys.toml"),
+            "[command]\ninputs = [\"gpg_dir\"]\nattach_to = \"hook_post_pacman_key_init\"\n",
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let name = CommandName::parse_value("hook_post_pacman_key_init").unwrap();
+        let definition = command_definition(
+            &manager,
+            &name,
+            manager.command(&name).unwrap(),
+            &crate::Phases::Install,
+        );
+        assert!(definition.ends_with("    extra_keys \"${gpg_dir}\"\n\n    pop_status\n}\n\n"));
+    }
+
+    #[test]
+    fn test_command_definitions_applicability() {
+        let definitions = builtin_command_definitions("pkg_kernel", &crate::Phases::Prepare);
+        assert!(definitions.contains("    check_distribution pkg_kernel arch\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("polish_only.toml"),
+            "[command]\napplies_to_phases = [\"polish\"]\nscript = \"echo polishing\"\n",
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
 
-        assert_eq!(&result, expected);
+        let name = CommandName::parse_value("polish_only").unwrap();
+        let command = manager.command(&name).unwrap();
+        assert!(
+            command_definition(&manager, &name, command, &crate::Phases::Polish)
+                .contains("echo polishing")
+        );
+        assert!(
+            !command_definition(&manager, &name, command, &crate::Phases::Prepare)
+                .contains("echo polishing")
+        );
+    }
+
+    #[test]
+    fn test_command_definitions_data_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("hook_write_pacman_conf.d")).unwrap();
+        std::fs::write(
+            dir.path().join("hook_write_pacman_conf.toml"),
+            "[command]\nscript = \"super_hook_write_pacman_conf\"\n",
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let name = CommandName::parse_value("hook_write_pacman_conf").unwrap();
+        let definition = command_definition(
+            &manager,
+            &name,
+            manager.command(&name).unwrap(),
+            &crate::Phases::Prepare,
+        );
+        assert!(definition
+            .contains("    local COMMAND_DATA_DIR=\"/tmp/clrm/data/hook_write_pacman_conf\"\n"));
+        assert!(definition.contains(
+            "    local COMMAND_DATA_DIR=\"/tmp/clrm/data/super_hook_write_pacman_conf\"\n"
+        ));
+
+        let scratch = tempfile::tempdir().unwrap();
+        let directories = data_directories(&manager, &name);
+        let (function, data) = directories
+            .iter()
+            .find(|(f, _)| f == "super_hook_write_pacman_conf")
+            .unwrap();
+        assert_eq!(function, "super_hook_write_pacman_conf");
+        let host_path = data.host_path(scratch.path()).unwrap();
+        assert!(host_path.join("pacman.conf").is_file());
+        let (_, data) = directories
+            .iter()
+            .find(|(f, _)| f == "hook_write_pacman_conf")
+            .unwrap();
+        assert_eq!(
+            data.host_path(scratch.path()).unwrap(),
+            dir.path().join("hook_write_pacman_conf.d")
+        );
+    }
+
+    #[test]
+    fn test_command_definitions_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("files.d")).unwrap();
+        std::fs::write(dir.path().join("files.d/foo.sh"), "echo foo").unwrap();
+        std::fs::write(
+            dir.path().join("files.toml"),
+            r#"
+[command]
+script = "echo body"
+
+[[command.files]]
+path = "/etc/it's.conf"
+content = "a = 'b'"
+
+[[command.files]]
+path = "/usr/bin/foo"
+source = "foo.sh"
+mode = 0o755
+phase = "install"
+"#,
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let name = CommandName::parse_value("files").unwrap();
+        let command = manager.command(&name).unwrap();
+        let definition = |phase| command_definition(&manager, &name, command, &phase);
+
+        assert!(definition(crate::Phases::Polish).contains(
+            "    install_file '/etc/it'\\''s.conf' 644 'root:root' --content 'a = '\\''b'\\'''\n"
+        ));
+        assert!(definition(crate::Phases::Install).contains(
+            "    install_file '/usr/bin/foo' 755 'root:root' \"${COMMAND_DATA_DIR}\"/'foo.sh'\n"
+        ));
+        assert!(!definition(crate::Phases::Prepare).contains("install_file"));
+        assert!(definition(crate::Phases::Test).contains(
+            "    check_file '/etc/it'\\''s.conf' 644\n    check_file '/usr/bin/foo' 755\n"
+        ));
+        // Files get installed and checked after the body ran:
+        assert!(definition(crate::Phases::Polish).contains("echo body\n\n    install_file "));
+        assert!(definition(crate::Phases::Test).contains("echo body\n\n    check_file "));
+
+        // Commands not running in a phase do not check their files in it either:
+        std::fs::write(
+            dir.path().join("files.toml"),
+            "[command]\napplies_to_phases = [\"polish\"]\n[[command.files]]\npath = \"/etc/foo\"\ncontent = \"foo\"\n",
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+        let command = manager.command(&name).unwrap();
+        let definition = |phase| command_definition(&manager, &name, command, &phase);
+        assert!(definition(crate::Phases::Polish).contains("    install_file '/etc/foo' "));
+        assert!(!definition(crate::Phases::Test).contains("check_file"));
+    }
+
+    #[test]
+    fn test_command_definitions_interpreter() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("py.toml"),
+            r#"
+[command]
+inputs = [{ name = "first" }, { name = "rest", variadic = true, optional = true }]
+interpreter = "/usr/bin/python3"
+script = """
+    import sys
+    if sys.argv[1]:
+        print(sys.argv[1])
+"""
+
+[command.phases.install]
+setup = """
+        import os
+"""
+"#,
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+
+        let name = CommandName::parse_value("py").unwrap();
+        let definition = mapped_command_definition(
+            &manager,
+            &name,
+            manager.command(&name).unwrap(),
+            &crate::Phases::Install,
+        );
+        assert!(definition.text.contains(
+            "    (\n        export ${__context_variables}\n        CLRM_PROTOCOL=\"/tmp/clrm/protocol\" \\\n            '/usr/bin/python3' \"/tmp/clrm/bodies/py\" \"${first}\" \"${@}\"\n    )\n"
+        ));
+        assert!(!definition.text.contains("import"));
+        assert_eq!(
+            definition.bodies,
+            vec![(
+                "py".to_string(),
+                "import os\n\nimport sys\nif sys.argv[1]:\n    print(sys.argv[1])\n".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_interpreter_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("sh_body.toml"),
+            r#"
+[command]
+inputs = ["first"]
+interpreter = "/bin/sh"
+script = """
+    printf '%s|%s|%s|%s|%s\\n' "${FOO}" "${LIST}" "${LATE}" "${PHASE_POLISH}" "${1}"
+"""
+"#,
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let manager = builder.build();
+        let name = CommandName::parse_value("sh_body").unwrap();
+        let definition = mapped_command_definition(
+            &manager,
+            &name,
+            manager.command(&name).unwrap(),
+            &crate::Phases::Polish,
+        );
+        for (function, body) in &definition.bodies {
+            std::fs::write(dir.path().join(function), body).unwrap();
+        }
+
+        let ctx = crate::context::ContextBuilder::new_test().build().unwrap();
+        let mut ctx = ctx.test_system();
+        ctx.set("FOO", "foo bar", true, false).unwrap();
+        let provenance = crate::context::Provenance {
+            command_stack: vec![],
+            phase: crate::Phases::Prepare,
+        };
+        ctx.append("LIST", OsStr::new("a"), &provenance).unwrap();
+        ctx.append("LIST", OsStr::new("b"), &provenance).unwrap();
+
+        // Run the definition on the host, with protocol messages discarded
+        let script = format!(
+            "exec 3>/dev/null\n{}{}{}{}export_constant LATE late\nsh_body first\n",
+            script_add_protocol().extract(),
+            script_add_phase_definitions().extract(),
+            definition
+                .text
+                .replace(BODIES_DIRECTORY, dir.path().to_str().unwrap()),
+            script_add_system_environment(&ctx).unwrap().extract(),
+        );
+        let output = std::process::Command::new("/bin/sh")
+            .args(["-e", "-c", &script])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "foo bar|a b|late|polish|first\n"
+        );
     }
 
     #[test]
-    fn test_shell_escape_unchanged() {
-        shell_escape(
-            r#"foobar 1, 2, 3, 4, XYZ # bar foo"#,
-            r#"foobar 1, 2, 3, 4, XYZ # bar foo"#,
+    fn test_protocol_helper() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        // Stand in for busybox by running its applets from the host
+        let busybox = dir.path().join("busybox");
+        std::fs::write(&busybox, "#!/bin/sh\nexec \"$@\"\n").unwrap();
+        std::fs::set_permissions(&busybox, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let channel = dir.path().join("channel");
+        let helper = dir.path().join("protocol");
+        std::fs::write(
+            &helper,
+            protocol_helper_script(busybox.to_str().unwrap(), channel.to_str().unwrap()),
+        )
+        .unwrap();
+        let run = |args: &[&OsStr]| {
+            std::process::Command::new("/bin/sh")
+                .arg(&helper)
+                .args(args)
+                .output()
+                .unwrap()
+        };
+
+        let value = OsStr::from_bytes(b"\"bar\"\n\xff ");
+        let output = run(&[OsStr::new("export_var"), OsStr::new("FOO"), value]);
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        let message = std::fs::read_to_string(&channel).unwrap();
+        assert_eq!(
+            crate::protocol::Message::parse(message.trim_end()).unwrap(),
+            crate::protocol::Message::Set {
+                name: "FOO".to_string(),
+                value: value.to_os_string(),
+            }
+        );
+
+        // Command names get encoded, too, so they can not break the message
+        let output = run(&[
+            OsStr::new("add_dependency"),
+            OsStr::new("FOO"),
+            OsStr::new("bad\",\"x\":\"y"),
+        ]);
+        assert!(output.status.success());
+        let message = std::fs::read_to_string(&channel).unwrap();
+        assert_eq!(
+            crate::protocol::Message::parse(message.trim_end()).unwrap(),
+            crate::protocol::Message::AddDependency {
+                name: "FOO".to_string(),
+                command: "bad\",\"x\":\"y".to_string(),
+            }
+        );
+
+        let output = run(&[OsStr::new("rm"), OsStr::new("-rf"), OsStr::new("/")]);
+        assert!(!output.status.success());
+        assert_eq!(
+            output.stdout,
+            b"Error in Agent script: Unknown agent protocol function rm\n"
         );
     }
 
     #[test]
-    fn test_shell_escape_quoted_double_quotes() {
-        shell_escape(r#"foo "b\"a\"z" bar"#, r#"foo \"b\\\"a\\\"z\" bar"#);
+    fn test_render_script_matches_created_script() {
+        let ctx = crate::context::ContextBuilder::new_test()
+            .timestamp("19700101.0000".to_string())
+            .unwrap()
+            .build()
+            .unwrap();
+        let name = CommandName::parse_value("_ensure_man_pages").unwrap();
+        let ctx = ctx.create_placeholder_build_context(&name, &[]).unwrap();
+
+        let rendered = render_script(&ctx, &name, &[], &crate::Phases::Polish).unwrap();
+        let created = create_script(&ctx, &name, &crate::Phases::Polish).unwrap();
+        assert_eq!(std::fs::read_to_string(created.script).unwrap(), rendered);
+        assert!(created.bodies_directory.is_dir());
+        assert!(created.protocol_helper.is_file());
+        assert!(rendered.contains("TIMESTAMP='19700101.0000'\nreadonly TIMESTAMP\n"));
+        assert!(rendered.ends_with("### <command>\n_ensure_man_pages\n### </command>\n\n### <footer>\nstatus \"Script reached the end for ${CURRENT_PHASE}\"\n### </footer>\n\n"));
+    }
+
+    #[test]
+    fn test_render_script_with_arguments() {
+        let ctx = crate::context::ContextBuilder::new_test()
+            .timestamp("19700101.0000".to_string())
+            .unwrap()
+            .build()
+            .unwrap();
+        let name = CommandName::parse_value("_distribution").unwrap();
+        assert!(ctx.create_placeholder_build_context(&name, &[]).is_err());
+
+        let arguments = vec!["arch".to_string()];
+        let build_ctx = ctx
+            .create_placeholder_build_context(&name, &arguments)
+            .unwrap();
+        let rendered =
+            render_script(&build_ctx, &name, &arguments, &crate::Phases::Prepare).unwrap();
+        assert!(rendered.contains("### <command>\n_distribution 'arch'\n### </command>\n"));
+
+        let name = CommandName::parse_value("add_packages").unwrap();
+        let arguments = vec!["a b".to_string(), "it's".to_string()];
+        let build_ctx = ctx
+            .create_placeholder_build_context(&name, &arguments)
+            .unwrap();
+        let rendered =
+            render_script(&build_ctx, &name, &arguments, &crate::Phases::Install).unwrap();
+        assert!(rendered.contains("### <command>\nadd_packages 'a b' 'it'\\''s'\n### </command>\n"));
+    }
+
+    #[test]
+    fn test_command_definitions_pruned() {
+        let definitions = builtin_command_definitions("pkg_kernel", &crate::Phases::Prepare);
+
+        assert!(definitions.contains("\npkg_kernel() {\n"));
+        assert!(definitions.contains("\nadd_packages() {\n"));
+        assert!(!definitions.contains("\nstrip_legal() {\n"));
+    }
+
+    #[test]
+    fn test_command_definitions_not_pruned_for_opaque_calls() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("_test_target_a.toml"),
+            "[command]\nscript = \"echo a\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("test_dynamic.toml"),
+            "[command]\nscript = \"\"\"\nid=a\n\"_test_target_${id}\"\n\"\"\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("test_python.toml"),
+            "[command]\ninterpreter = \"/usr/bin/python3\"\nscript = \"print()\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("test_plain.toml"),
+            "[command]\nscript = \"echo plain\"\n",
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let ctx = crate::context::ContextBuilder::new(
+            crate::printer::Printer::new(&crate::printer::LogLevel::Off, false),
+            builder.build(),
+        )
+        .build()
+        .unwrap();
+        let definitions = |start: &str| {
+            let start = CommandName::parse_value(start).unwrap();
+            let ctx = ctx.create_placeholder_build_context(&start, &[]).unwrap();
+            script_add_command_definitions(&ctx, &start, &crate::Phases::Install)
+                .unwrap()
+                .0
+                .extract()
+        };
+
+        let plain = definitions("test_plain");
+        assert!(!plain.contains("\n_test_target_a() {\n"));
+        for opaque in ["test_dynamic", "test_python"] {
+            let definitions = definitions(opaque);
+            assert!(definitions.contains("\n_test_target_a() {\n"));
+            assert!(definitions.contains("\ntest_plain() {\n"));
+        }
+    }
+
+    #[test]
+    fn test_source_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mapped.toml");
+        std::fs::write(
+            &path,
+            r#"[command]
+help = "x"
+
+script = """
+    echo one
+    echo two
+"""
+
+[command.phases.polish]
+script = """
+    echo polish
+"""
+"#,
+        )
+        .unwrap();
+        let mut builder = crate::commands::CommandManagerBuilder::default();
+        builder.scan_for_commands(dir.path()).unwrap();
+        let ctx = crate::context::ContextBuilder::new(
+            crate::printer::Printer::new(&crate::printer::LogLevel::Off, false),
+            builder.build(),
+        )
+        .build()
+        .unwrap();
+        let name = CommandName::parse_value("mapped").unwrap();
+        let ctx = ctx.create_placeholder_build_context(&name, &[]).unwrap();
+
+        let (script, source_map, _) =
+            assemble_script(&ctx, &name, &[], &crate::Phases::Polish).unwrap();
+        let script_line = |text: &str| script.lines().position(|l| l == text).unwrap() + 1;
+
+        let location = source_map.lookup(script_line("    echo two")).unwrap();
+        assert_eq!(location.command, name);
+        assert_eq!(location.function, "mapped");
+        assert_eq!(location.source_location, path.to_string_lossy());
+        assert_eq!(location.line, Some(6));
+        assert_eq!(
+            source_map
+                .lookup(script_line("    echo polish"))
+                .unwrap()
+                .line,
+            Some(11)
+        );
+        assert_eq!(
+            source_map.lookup(script_line("mapped() {")).unwrap().line,
+            None
+        );
+        assert_eq!(source_map.function("mapped").unwrap().line, None);
+        assert!(source_map.lookup(1).is_none());
     }
 }
@@ -77,7 +77,7 @@ impl Command {
         self
     }
 
-    /// Removes an environment variable mapping.
+    // Removes an environment variable mapping.
     // pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
     //     self.environment.remove(k);
     //     self
@@ -15,7 +15,12 @@
 )]
 // Clippy:
 #![warn(clippy::all, clippy::pedantic)]
-#![allow(clippy::module_name_repetitions, clippy::let_unit_value)]
+#![allow(
+    clippy::module_name_repetitions,
+    clippy::let_unit_value,
+    clippy::struct_field_names,
+    clippy::unnecessary_debug_formatting
+)]
 
 use std::{
     ffi::OsString,
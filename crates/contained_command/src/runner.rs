@@ -237,9 +237,7 @@ impl Runtime for Nspawn {
             let effective_uid = std::fs::metadata("/proc/self")
                 .map(|m| m.uid())
                 .expect("/proc/self should be accessible to this process!");
-            args.push(OsString::from(
-                format!("--private-users={effective_uid}:1",),
-            ));
+            args.push(OsString::from(format!("--private-users={effective_uid}:1")));
         }
 
         if let Some(current_directory) = &command.current_directory {
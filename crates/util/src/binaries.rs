@@ -146,10 +146,10 @@ fn resolve_directory_impl(
             return Ok(extend_dir(dir, &current_dir, 0));
         }
     }
-    return Err(crate::Error::InvalidDirectory {
+    Err(crate::Error::InvalidDirectory {
         reason: "Could not resolve".to_string(),
         directory: dir.as_os_str().to_os_string(),
-    });
+    })
 }
 
 #[cfg(test)]